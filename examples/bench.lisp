@@ -0,0 +1,5 @@
+; Interpreter timing: time target/release/ab_lisp examples/bench.lisp
+(function fib (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))
+(print (fib 25) "\n")
+(function loop (n) (if (= n 0) 0 (loop (- n 1))))
+(print (loop 1000000) "\n")
@@ -0,0 +1,14 @@
+(function map (f list)
+	(if list
+		(cons (f (head list)) (map f (tail list)))
+		()
+	)
+)
+
+(function make-adder (n)
+	(lambda (x) (+ x n))
+)
+
+(let a '(1 2 3))
+(print (map (lambda (x) (* x x)) a) "\n")
+(print (map (make-adder 10) a) "\n")
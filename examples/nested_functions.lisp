@@ -7,6 +7,7 @@
 	)
 	(debug foo bar)
 	(foo)
+	foo
 )
-(main)
-(debug foo bar)
\ No newline at end of file
+(let f (main))
+(f)
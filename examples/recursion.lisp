@@ -7,4 +7,4 @@
 		)
 	)
 )
-(rec)
+(rec 0)
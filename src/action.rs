@@ -1,4 +1,6 @@
-use crate::{value::Value, location::Location};
+use std::rc::Rc;
+
+use crate::{value::Value, location::Location, error::{Error, ErrorKind}, state::Limit};
 
 #[derive(Debug, Clone)]
 pub struct Action {
@@ -11,7 +13,71 @@ pub enum ActionVal {
 	Ident(String),
 	Literal(Value),
 	Group {
-		content: Vec<Action>,
+		content: Rc<[Action]>,
 		quoted: bool
+	},
+	/// `` `x ``: builds `x` as data, evaluating only the unquoted parts
+	Quasiquote(Box<Action>),
+	/// `,x` inside a quasiquote
+	Unquote(Box<Action>),
+	/// `,@x` inside a quasiquote, splices the list `x` into the enclosing one
+	UnquoteSplicing(Box<Action>)
+}
+
+impl Action {
+	/// Turns code into data without evaluating anything: identifiers become
+	/// symbols and groups become lists. Reader shorthands are spelled out, so
+	/// `'(a)` becomes `(list a)` and `` `a `` becomes `(quasiquote a)`.
+	pub fn to_value(&self) -> Value {
+		let tagged = |tag: &str, inner: &Action| Value::list(&[Value::Symbol(tag.into()), inner.to_value()]);
+		match &self.val {
+			ActionVal::Ident(name) => Value::Symbol(name.clone()),
+			ActionVal::Literal(val) => val.clone(),
+			ActionVal::Group { content, quoted } => {
+				let mut items = Vec::with_capacity(content.len() + 1);
+				if *quoted {
+					items.push(Value::Symbol("list".into()));
+				}
+				items.extend(content.iter().map(Action::to_value));
+				Value::list(&items)
+			}
+			ActionVal::Quasiquote(inner) => tagged("quasiquote", inner),
+			ActionVal::Unquote(inner) => tagged("unquote", inner),
+			ActionVal::UnquoteSplicing(inner) => tagged("unquote-splicing", inner)
+		}
+	}
+
+	/// Inverse of [`Action::to_value`]. Data carries no positions, so every
+	/// produced action points at `location`. Compiling code uses the native
+	/// stack, so lists nested deeper than `max_depth` are refused.
+	pub fn from_value(value: &Value, location: &Location, max_depth: Option<usize>) -> Result<Action, Error> {
+		Action::from_value_at(value, location, max_depth.unwrap_or(usize::MAX))
+	}
+
+	fn from_value_at(value: &Value, location: &Location, depth: usize) -> Result<Action, Error> {
+		let val = match value {
+			Value::Symbol(name) => ActionVal::Ident(name.clone()),
+			Value::List(list) if !list.is_nil() => {
+				let Some(depth) = depth.checked_sub(1) else {
+					return Err(Error::new_at(ErrorKind::LimitExceeded(Limit::CodeDepth), location.clone()));
+				};
+				let items = list.collect();
+				let inner = |item| Action::from_value_at(item, location, depth).map(Box::new);
+				match (&items[0], items.len()) {
+					(Value::Symbol(tag), 2) if tag == "quasiquote" => ActionVal::Quasiquote(inner(&items[1])?),
+					(Value::Symbol(tag), 2) if tag == "unquote" => ActionVal::Unquote(inner(&items[1])?),
+					(Value::Symbol(tag), 2) if tag == "unquote-splicing" => ActionVal::UnquoteSplicing(inner(&items[1])?),
+					_ => ActionVal::Group {
+						content: items.iter().map(|item| Action::from_value_at(item, location, depth)).collect::<Result<_, _>>()?,
+						quoted: false
+					}
+				}
+			}
+			other => ActionVal::Literal(other.clone())
+		};
+		Ok(Action {
+			val,
+			location: location.clone()
+		})
 	}
 }
@@ -0,0 +1,145 @@
+use std::{fmt::{self, Debug}, rc::Rc};
+
+use crate::{location::Location, value::{Value, function::Arity}};
+
+/// Where a variable lives. Resolved by the compiler, so running code never
+/// looks variables up by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Var {
+	/// Slot in the frame of the running function: arguments first, then locals
+	Local(usize),
+	/// Variable of an enclosing function, captured when the running one was created
+	Captured(usize),
+	/// Slot in the table of globals
+	Global(usize)
+}
+
+/// Instruction of the stack machine. Operands index the tables of the
+/// [`Chunk`] holding the instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+	Nil,
+	Const(usize),
+	Get(Var),
+	/// Assigns the value on top of the stack, which stays there
+	Set(Var),
+	/// Like [`Op::Set`], but also defines a global variable
+	Let(Var),
+	Pop,
+	Jump(usize),
+	/// Pops a value and jumps if it is false
+	JumpIfNot(usize),
+	/// Jumps to `.1` unless argument slot `.0` was left out of the call
+	JumpIfGiven(usize, usize),
+	/// Calls the function below the given number of arguments
+	Call(usize),
+	/// Like [`Op::Call`], but a Lisp function replaces the frame of the caller
+	TailCall(usize),
+	Return,
+	/// Pops the given number of values into a list
+	List(usize),
+	/// Pops the given number of lists and appends them
+	Concat(usize),
+	/// Fails unless the value on top of the stack is a list
+	ExpectList,
+	/// Creates a function from `protos[i]`, capturing the variables `captures[i]`
+	Function(usize),
+	/// Creates an object sharing the variables listed in `objects[i]`
+	Object(usize),
+	/// Pops a value and runs it as code
+	Eval,
+	/// Starts collecting output for `with-output-to-string`
+	BeginCapture,
+	/// Pushes the output collected since the matching [`Op::BeginCapture`]
+	EndCapture
+}
+
+/// Compiled code of a top-level form or of a function body.
+#[derive(Default)]
+pub struct Chunk {
+	pub(crate) code: Vec<Op>,
+	/// Source of every instruction, for error reports
+	pub(crate) locations: Vec<Location>,
+	pub(crate) constants: Vec<Value>,
+	pub(crate) protos: Vec<Rc<Proto>>,
+	/// Variables captured by each function of `protos`, in the order of [`Proto::free`]
+	pub(crate) captures: Vec<Vec<Var>>,
+	/// Variables shared by each object, with their names
+	pub(crate) objects: Vec<Vec<(String, Var)>>
+}
+
+impl Chunk {
+	pub fn location(&self, pc: usize) -> &Location {
+		&self.locations[pc]
+	}
+}
+
+impl Debug for Chunk {
+	/// One instruction per line, with constants resolved.
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for (pc, op) in self.code.iter().enumerate() {
+			write!(f, "{pc:4} {op:?}")?;
+			if let Op::Const(i) = op {
+				write!(f, "\t{:?}", self.constants[*i])?;
+			}
+			writeln!(f)?;
+		}
+		Ok(())
+	}
+}
+
+/// Everything known about a Lisp function before it is created at runtime.
+/// Opaque outside the crate, the bytecode is an implementation detail.
+pub struct Proto {
+	pub(crate) chunk: Rc<Chunk>,
+	/// Names of all parameters, in the order of [`Params`]
+	pub(crate) args: Vec<String>,
+	pub(crate) params: Params,
+	/// Variables declared by the body, created when the function is called
+	/// so closures defined earlier in the body can capture them
+	pub(crate) locals: Vec<String>,
+	/// Variables of enclosing scopes the body refers to, including those
+	/// listed by `funcap`
+	pub(crate) free: Vec<String>,
+	/// Number of leading `free` variables listed by `funcap`. A defined
+	/// global among them is captured as the variable itself, like a local.
+	pub(crate) listed: usize,
+	/// Variables of the enclosing functions declared before this one, only
+	/// used to suggest names in errors
+	pub(crate) enclosing: Vec<String>,
+	/// Where the function is defined
+	pub(crate) location: Location
+}
+
+impl Proto {
+	/// Number of slots in a frame of the function.
+	pub(crate) fn frame_size(&self) -> usize {
+		self.args.len() + self.locals.len()
+	}
+
+	pub(crate) fn arity(&self) -> Arity {
+		let params = &self.params;
+		Arity {
+			min: params.required,
+			max: (!params.rest).then_some(params.required + params.optional + 2 * params.keys)
+		}
+	}
+}
+
+/// Shape of a parameter list: required parameters, then `&optional`, `&rest`
+/// and `&key` ones.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Params {
+	pub(crate) required: usize,
+	pub(crate) optional: usize,
+	pub(crate) rest: bool,
+	/// Parameters passed by name, as an atom followed by the value
+	pub(crate) keys: usize
+}
+
+impl Params {
+	/// Slot of the first keyword parameter.
+	pub(crate) fn first_key(&self) -> usize {
+		self.required + self.optional + usize::from(self.rest)
+	}
+}
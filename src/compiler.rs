@@ -0,0 +1,560 @@
+use std::rc::Rc;
+
+use crate::{action::{Action, ActionVal}, bytecode::{Chunk, Op, Params, Proto, Var}, error::Error, location::Location, macros::{Macro, SyntaxRules}, state::State, value::{Value, function::{Function, Capture}}};
+
+/// Variables of the function being compiled.
+#[derive(Default)]
+struct Names {
+	args: Vec<String>,
+	locals: Vec<String>,
+	free: Vec<String>
+}
+
+impl Names {
+	fn slot(&self, name: &str) -> Option<usize> {
+		self.args.iter().chain(&self.locals).position(|bound| bound == name)
+	}
+}
+
+/// Default value expressions of parameters, with the slot each one fills.
+type Defaults<'a> = Vec<(usize, &'a Action)>;
+
+/// Compiles one top-level form at a time, since a macro is only known once
+/// the form defining it has run. Macro calls are expanded here, so running
+/// the chunk never looks at the `Action` tree again.
+///
+/// Code run by `eval` inside a function gets the function's prototype as
+/// `context` and can use its variables.
+pub(crate) fn compile(abl: &mut State, action: &Action, context: Option<Rc<Proto>>) -> Result<Chunk, Error> {
+	let enclosing = context.as_ref().map(|context| context.enclosing.clone()).unwrap_or_default();
+	let mut compiler = Compiler {
+		abl,
+		chunk: Chunk::default(),
+		names: None,
+		context,
+		enclosing,
+		pending: Vec::new()
+	};
+	compiler.expr(action, false)?;
+	compiler.emit(Op::Return, &action.location);
+	compiler.resolve_pending();
+	Ok(compiler.chunk)
+}
+
+struct Compiler<'a> {
+	abl: &'a mut State,
+	chunk: Chunk,
+	/// `None` at top level, where variables are globals
+	names: Option<Names>,
+	context: Option<Rc<Proto>>,
+	/// See [`Proto::enclosing`]
+	enclosing: Vec<String>,
+	/// Variable `.1` captured by function `.0` of the chunk that wasn't bound
+	/// where the function is defined. It's resolved once every local of the
+	/// function being compiled is known, so closures can refer to functions
+	/// defined after them.
+	pending: Vec<(usize, usize, String)>
+}
+
+impl<'a> Compiler<'a> {
+	fn emit(&mut self, op: Op, location: &Location) -> usize {
+		self.chunk.code.push(op);
+		self.chunk.locations.push(location.clone());
+		self.chunk.code.len() - 1
+	}
+
+	/// Points the jump at `at` to the next instruction.
+	fn patch(&mut self, at: usize) {
+		let target = self.chunk.code.len();
+		match &mut self.chunk.code[at] {
+			Op::Jump(to) | Op::JumpIfNot(to) | Op::JumpIfGiven(_, to) => *to = target,
+			_ => ()
+		}
+	}
+
+	fn constant(&mut self, value: Value, location: &Location) {
+		self.chunk.constants.push(value);
+		let index = self.chunk.constants.len() - 1;
+		self.emit(Op::Const(index), location);
+	}
+
+	fn get(&mut self, name: &str, location: &Location) {
+		let var = self.resolve(name);
+		self.emit(Op::Get(var), location);
+	}
+
+	fn set(&mut self, name: &str, location: &Location) {
+		let var = self.resolve(name);
+		self.emit(Op::Set(var), location);
+	}
+
+	/// Defines `name` as a local of the function being compiled, or as a
+	/// global at top level, and assigns it. Code of the body before the
+	/// definition still refers to the outer variable.
+	fn define(&mut self, name: &str, location: &Location) {
+		if let Some(names) = &mut self.names {
+			if names.slot(name).is_none() {
+				names.locals.push(name.into());
+			}
+		}
+		let var = self.resolve(name);
+		self.emit(Op::Let(var), location);
+	}
+
+	fn resolve(&mut self, name: &str) -> Var {
+		if let Some(names) = &mut self.names {
+			if let Some(slot) = names.slot(name) {
+				return Var::Local(slot);
+			}
+			// Whether the enclosing scopes know the variable is only found out
+			// when they are resolved, until then it's captured
+			return match names.free.iter().position(|free| free == name) {
+				Some(index) => Var::Captured(index),
+				None => {
+					names.free.push(name.into());
+					Var::Captured(names.free.len() - 1)
+				}
+			};
+		}
+		if let Some(context) = &self.context {
+			if let Some(slot) = context.args.iter().chain(&context.locals).position(|bound| bound == name) {
+				return Var::Local(slot);
+			}
+			if let Some(index) = context.free.iter().position(|free| free == name) {
+				return Var::Captured(index);
+			}
+		}
+		Var::Global(self.abl.global_slot(name))
+	}
+
+	/// Variables visible to a function defined here, innermost first.
+	fn scope(&self) -> Vec<String> {
+		let bound = match (&self.names, &self.context) {
+			(Some(names), _) => names.args.iter().chain(&names.locals).cloned().collect(),
+			(None, Some(context)) => context.args.iter().chain(&context.locals).cloned().collect(),
+			(None, None) => Vec::new()
+		};
+		[bound, self.enclosing.clone()].concat()
+	}
+
+	/// Whether `name` is bound at this point of the code, by a variable of
+	/// this or an enclosing function or by a defined global.
+	fn is_bound(&self, name: &str) -> bool {
+		self.scope().iter().any(|bound| bound == name)
+			|| self.names.as_ref().is_some_and(|names| names.free.iter().any(|free| free == name))
+			|| self.abl.is_defined(name)
+	}
+
+	fn resolve_pending(&mut self) {
+		for (func, index, name) in std::mem::take(&mut self.pending) {
+			self.chunk.captures[func][index] = self.resolve(&name);
+		}
+	}
+
+	/// Compiles `actions` leaving the value of the last one on the stack.
+	fn body(&mut self, actions: &[Action], location: &Location, tail: bool) -> Result<(), Error> {
+		match actions.split_last() {
+			Some((last, init)) => {
+				for action in init {
+					self.expr(action, false)?;
+					self.emit(Op::Pop, &action.location);
+				}
+				self.expr(last, tail)
+			}
+			None => {
+				self.emit(Op::Nil, location);
+				Ok(())
+			}
+		}
+	}
+
+	/// Compiles code leaving its value on the stack. Calls in `tail` position
+	/// replace the frame of the function being compiled.
+	fn expr(&mut self, action: &Action, tail: bool) -> Result<(), Error> {
+		let location = &action.location;
+		match &action.val {
+			ActionVal::Literal(val) => self.constant(val.clone(), location),
+			ActionVal::Ident(name) => self.get(name, location),
+			ActionVal::Group { content, quoted: true } => {
+				if content.is_empty() {
+					self.emit(Op::Nil, location);
+				} else {
+					for item in content.iter() {
+						self.expr(item, false)?;
+					}
+					self.emit(Op::List(content.len()), location);
+				}
+			}
+			ActionVal::Quasiquote(template) => self.quasiquote(template, 1)?,
+			ActionVal::Unquote(_) | ActionVal::UnquoteSplicing(_) => {
+				return Err(Error::syntax(location.clone(), "Unquote outside of a quasiquote"));
+			}
+			ActionVal::Group { content, quoted: false } => match content.first().map(|head| &head.val) {
+				None => {
+					self.emit(Op::Nil, location);
+				}
+				Some(ActionVal::Ident(head)) => self.form(head, action, content, tail)?,
+				Some(_) => self.call(content, tail)?
+			}
+		}
+		Ok(())
+	}
+
+	fn form(&mut self, head: &str, action: &Action, content: &[Action], tail: bool) -> Result<(), Error> {
+		let location = &content[0].location;
+		match head {
+			"do" => self.body(&content[1..], location, tail)?,
+			"let" => self.assign(&content[1..], location, false)?,
+			"set" => self.assign(&content[1..], location, true)?,
+			"if" => {
+				if content.len() != 4 {
+					return Err(Error::syntax(location.clone(), "`if` expects a condition and two branches"));
+				}
+				self.expr(&content[1], false)?;
+				let to_else = self.emit(Op::JumpIfNot(0), location);
+				self.expr(&content[2], tail)?;
+				let to_end = self.emit(Op::Jump(0), location);
+				self.patch(to_else);
+				self.expr(&content[3], tail)?;
+				self.patch(to_end);
+			}
+			"while" => {
+				if content.len() != 3 {
+					return Err(Error::syntax(location.clone(), "`while` expects a condition and a body"));
+				}
+				let start = self.chunk.code.len();
+				self.expr(&content[1], false)?;
+				let to_end = self.emit(Op::JumpIfNot(0), location);
+				self.expr(&content[2], false)?;
+				self.emit(Op::Pop, location);
+				self.emit(Op::Jump(start), location);
+				self.patch(to_end);
+				self.emit(Op::Nil, location);
+			}
+			"function" => {
+				if content.len() < 4 {
+					return Err(Error::syntax(location.clone(), "`function` expects a name, an argument list and a body"));
+				}
+				let name = Self::ident(&content[1], "Expected a function name")?;
+				let args = Self::group(&content[2], "Expected an argument list")?;
+				let proto = self.function(args, &content[3..], Vec::new(), location)?;
+				self.emit(Op::Function(proto), location);
+				self.define(name, location);
+			}
+			"lambda" | "fn" => {
+				if content.len() < 3 {
+					return Err(Error::syntax(location.clone(), "`lambda` expects an argument list and a body"));
+				}
+				let args = Self::group(&content[1], "Expected an argument list")?;
+				let proto = self.function(args, &content[2..], Vec::new(), location)?;
+				self.emit(Op::Function(proto), location);
+			}
+			"funcap" => {
+				if content.len() < 5 {
+					return Err(Error::syntax(location.clone(), "`funcap` expects a name, an argument list, a capture list and a body"));
+				}
+				let name = Self::ident(&content[1], "Expected a function name")?;
+				let args = Self::group(&content[2], "Expected an argument list")?;
+				let captures = Self::group(&content[3], "Expected a capture list")?;
+				let captures = captures.iter()
+					.filter_map(|cap| match &cap.val {
+						ActionVal::Ident(name) => Some(name.clone()),
+						_ => None
+					})
+					.collect::<Vec<_>>();
+				let proto = self.function(args, &content[4..], captures, location)?;
+				self.emit(Op::Function(proto), location);
+				self.define(name, location);
+			}
+			"quote" => {
+				if content.len() != 2 {
+					return Err(Error::syntax(location.clone(), "`quote` expects a single expression"));
+				}
+				self.constant(content[1].to_value(), location);
+			}
+			// Compiled in place so the code can use local variables, `eval`
+			// used as a value is a builtin running in global scope
+			"eval" => {
+				if content.len() != 2 {
+					return Err(Error::syntax(location.clone(), "`eval` expects a single expression"));
+				}
+				self.expr(&content[1], false)?;
+				self.emit(Op::Eval, location);
+			}
+			"with-output-to-string" => {
+				self.emit(Op::BeginCapture, location);
+				self.body(&content[1..], location, false)?;
+				self.emit(Op::Pop, location);
+				self.emit(Op::EndCapture, location);
+			}
+			"object" => {
+				let object = self.chunk.objects.len();
+				let mut vars = Vec::with_capacity(content.len() - 1);
+				for item in &content[1..] {
+					let name = Self::ident(item, "Expected a variable name")?;
+					vars.push((name.to_string(), self.resolve(name)));
+				}
+				self.chunk.objects.push(vars);
+				self.emit(Op::Object(object), location);
+			}
+			"defmacro" => {
+				if content.len() < 4 {
+					return Err(Error::syntax(location.clone(), "`defmacro` expects a name, an argument list and a body"));
+				}
+				let name = Self::ident(&content[1], "Expected a macro name")?;
+				let args = Self::group(&content[2], "Expected an argument list")?;
+				let proto = self.function(args, &content[3..], Vec::new(), location)?;
+				// Expansion happens before the enclosing code runs, so a
+				// macro can only use global variables
+				let proto = self.chunk.protos[proto].clone();
+				let mut func = Function::lang(proto.clone());
+				func.captures = proto.free.iter().map(|name| Capture::Global(self.abl.global_slot(name))).collect();
+				self.abl.define_macro(name, Macro::Procedural(Rc::new(func)));
+				self.emit(Op::Nil, location);
+			}
+			"define-syntax" => {
+				if content.len() != 3 {
+					return Err(Error::syntax(location.clone(), "`define-syntax` expects a name and a `syntax-rules` form"));
+				}
+				let name = Self::ident(&content[1], "Expected a macro name")?;
+				let rules = SyntaxRules::new(name, &content[2])?;
+				self.abl.define_macro(name, Macro::Rules(Rc::new(rules)));
+				self.emit(Op::Nil, location);
+			}
+			_ => match self.abl.expand_macro_action(action)? {
+				Some(expanded) => {
+					self.abl.enter_expansion().map_err(|err| err.or_at(location))?;
+					let res = self.expr(&expanded, tail);
+					self.abl.leave_expansion();
+					res?
+				}
+				None => self.call(content, tail)?
+			}
+		}
+		Ok(())
+	}
+
+	fn call(&mut self, content: &[Action], tail: bool) -> Result<(), Error> {
+		for item in content {
+			self.expr(item, false)?;
+		}
+		let location = &content[0].location;
+		let argc = content.len() - 1;
+		if tail && self.names.is_some() {
+			self.emit(Op::TailCall(argc), location);
+			self.emit(Op::Return, location);
+		} else {
+			self.emit(Op::Call(argc), location);
+		}
+		Ok(())
+	}
+
+	/// `let`/`set` pairs. The value of the form is the last assigned value.
+	fn assign(&mut self, pairs: &[Action], location: &Location, set: bool) -> Result<(), Error> {
+		if pairs.is_empty() {
+			self.emit(Op::Nil, location);
+		}
+		for (i, pair) in pairs.chunks(2).enumerate() {
+			if i > 0 {
+				self.emit(Op::Pop, location);
+			}
+			let name = Self::ident(&pair[0], "Expected a variable name")?;
+			match pair.get(1) {
+				Some(value) => self.expr(value, false)?,
+				None => {
+					self.emit(Op::Nil, &pair[0].location);
+				}
+			}
+			if set {
+				self.set(name, &pair[0].location);
+			} else {
+				self.define(name, &pair[0].location);
+			}
+		}
+		Ok(())
+	}
+
+	/// Compiles a function body into a prototype of this chunk, returning its
+	/// index. `captures` are captured even if the body doesn't use them.
+	fn function(&mut self, raw_args: &[Action], body: &[Action], captures: Vec<String>, location: &Location) -> Result<usize, Error> {
+		let (params, args, defaults) = Self::params(raw_args)?;
+		let enclosing = self.scope();
+		let listed = captures.len();
+		let mut compiler = Compiler {
+			abl: &mut *self.abl,
+			chunk: Chunk::default(),
+			names: Some(Names {
+				args,
+				locals: Vec::new(),
+				free: captures
+			}),
+			context: None,
+			enclosing: enclosing.clone(),
+			pending: Vec::new()
+		};
+		for (slot, default) in defaults {
+			let skip = compiler.emit(Op::JumpIfGiven(slot, 0), &default.location);
+			compiler.expr(default, false)?;
+			compiler.emit(Op::Set(Var::Local(slot)), &default.location);
+			compiler.emit(Op::Pop, &default.location);
+			compiler.patch(skip);
+		}
+		compiler.body(body, location, true)?;
+		compiler.emit(Op::Return, location);
+		compiler.resolve_pending();
+		let Compiler { chunk, names, .. } = compiler;
+		let names = names.unwrap_or_default();
+
+		let func = self.chunk.protos.len();
+		self.chunk.captures.push(vec![Var::Global(0); names.free.len()]);
+		for (index, name) in names.free.iter().enumerate() {
+			if self.is_bound(name) {
+				self.chunk.captures[func][index] = self.resolve(name);
+			} else {
+				self.pending.push((func, index, name.clone()));
+			}
+		}
+		self.chunk.protos.push(Rc::new(Proto {
+			chunk: Rc::new(chunk),
+			args: names.args,
+			params,
+			locals: names.locals,
+			free: names.free,
+			listed,
+			enclosing,
+			location: location.clone()
+		}));
+		Ok(func)
+	}
+
+	/// Reads a parameter list. Optional and keyword parameters may be written
+	/// `(name default)`.
+	fn params(raw_args: &[Action]) -> Result<(Params, Vec<String>, Defaults<'_>), Error> {
+		#[derive(PartialEq, PartialOrd)]
+		enum Section { Required, Optional, Rest, Key }
+
+		let mut params = Params::default();
+		let mut args = Vec::with_capacity(raw_args.len());
+		let mut defaults = Vec::new();
+		let mut section = Section::Required;
+		for arg in raw_args {
+			let next = match &arg.val {
+				ActionVal::Ident(marker) if marker == "&optional" => Some(Section::Optional),
+				ActionVal::Ident(marker) if marker == "&rest" => Some(Section::Rest),
+				ActionVal::Ident(marker) if marker == "&key" => Some(Section::Key),
+				ActionVal::Ident(marker) if marker.starts_with('&') => {
+					return Err(Error::syntax(arg.location.clone(), &format!("Unknown parameter marker `{marker}`")));
+				}
+				_ => None
+			};
+			if let Some(next) = next {
+				if section == Section::Rest && !params.rest {
+					return Err(Error::syntax(arg.location.clone(), "Expected a parameter after `&rest`"));
+				}
+				if next <= section {
+					return Err(Error::syntax(arg.location.clone(), "Parameter markers must appear once, in the order `&optional`, `&rest`, `&key`"));
+				}
+				section = next;
+				continue;
+			}
+
+			let (name, default) = match &arg.val {
+				ActionVal::Group { content, quoted: false } if content.len() == 2 && matches!(section, Section::Optional | Section::Key) => {
+					(Self::ident(&content[0], "Expected an argument name")?, Some(&content[1]))
+				}
+				_ => (Self::ident(arg, "Expected an argument name")?, None)
+			};
+			if let Some(default) = default {
+				defaults.push((args.len(), default));
+			}
+			args.push(name.to_string());
+			match section {
+				Section::Required => params.required += 1,
+				Section::Optional => params.optional += 1,
+				Section::Rest if !params.rest => params.rest = true,
+				Section::Rest => return Err(Error::syntax(arg.location.clone(), "`&rest` takes a single parameter")),
+				Section::Key => params.keys += 1
+			}
+		}
+		if section == Section::Rest && !params.rest {
+			let location = &raw_args[raw_args.len() - 1].location;
+			return Err(Error::syntax(location.clone(), "Expected a parameter after `&rest`"));
+		}
+		Ok((params, args, defaults))
+	}
+
+	/// Builds the data described by a quasiquote template. `depth` counts the
+	/// enclosing quasiquotes, only unquotes at depth 1 are evaluated.
+	fn quasiquote(&mut self, action: &Action, depth: usize) -> Result<(), Error> {
+		let location = &action.location;
+		match &action.val {
+			ActionVal::Ident(name) => self.constant(Value::Symbol(name.clone()), location),
+			ActionVal::Literal(val) => self.constant(val.clone(), location),
+			ActionVal::Group { content, quoted } => {
+				// Runs of plain items become lists, spliced lists are appended to them
+				let mut items = 0;
+				let mut parts = 0;
+				if *quoted {
+					self.constant(Value::Symbol("list".into()), location);
+					items += 1;
+				}
+				for item in content.iter() {
+					match &item.val {
+						ActionVal::UnquoteSplicing(inner) if depth == 1 => {
+							if items > 0 {
+								self.emit(Op::List(items), location);
+								parts += 1;
+								items = 0;
+							}
+							self.expr(inner, false)?;
+							self.emit(Op::ExpectList, &inner.location);
+							parts += 1;
+						}
+						_ => {
+							self.quasiquote(item, depth)?;
+							items += 1;
+						}
+					}
+				}
+				if parts == 0 {
+					self.emit(Op::List(items), location);
+				} else {
+					if items > 0 {
+						self.emit(Op::List(items), location);
+						parts += 1;
+					}
+					self.emit(Op::Concat(parts), location);
+				}
+			}
+			ActionVal::Quasiquote(inner) => self.tagged("quasiquote", inner, depth + 1)?,
+			ActionVal::Unquote(inner) if depth == 1 => self.expr(inner, false)?,
+			ActionVal::Unquote(inner) => self.tagged("unquote", inner, depth - 1)?,
+			ActionVal::UnquoteSplicing(_) if depth == 1 => {
+				return Err(Error::syntax(location.clone(), "`,@` can only be used inside a list"));
+			}
+			ActionVal::UnquoteSplicing(inner) => self.tagged("unquote-splicing", inner, depth - 1)?
+		}
+		Ok(())
+	}
+
+	fn tagged(&mut self, tag: &str, inner: &Action, depth: usize) -> Result<(), Error> {
+		self.constant(Value::Symbol(tag.into()), &inner.location);
+		self.quasiquote(inner, depth)?;
+		self.emit(Op::List(2), &inner.location);
+		Ok(())
+	}
+
+	fn ident<'b>(action: &'b Action, message: &str) -> Result<&'b str, Error> {
+		match &action.val {
+			ActionVal::Ident(name) => Ok(name),
+			_ => Err(Error::syntax(action.location.clone(), message))
+		}
+	}
+
+	fn group<'b>(action: &'b Action, message: &str) -> Result<&'b [Action], Error> {
+		match &action.val {
+			ActionVal::Group { content, .. } => Ok(content),
+			_ => Err(Error::syntax(action.location.clone(), message))
+		}
+	}
+}
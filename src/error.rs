@@ -1,8 +1,8 @@
 use std::fmt::{self, Display};
 
-use ariadne::{Label, Source, Span};
+use ariadne::Label;
 
-use crate::location::{Location, self};
+use crate::{location::Location, state::Limit, value::function::Arity};
 
 #[derive(Debug)]
 pub struct Error {
@@ -24,65 +24,200 @@ impl Error {
 			kind
 		}
 	}
+
+	/// Attaches `location` unless the error already points somewhere more precise.
+	/// Code built from data by the host, e.g. by the `eval` builtin, points at
+	/// [`Location::host`], so such errors are moved to the call site too.
+	pub fn or_at(mut self, location: &Location) -> Self {
+		if self.location.as_ref().is_none_or(Location::is_host) {
+			if let ErrorKind::Syntax(errors) = &mut self.kind {
+				for err in errors.iter_mut().filter(|err| err.location.is_host()) {
+					err.location = location.clone();
+				}
+			}
+			self.location = Some(location.clone());
+		}
+		self
+	}
+
+	/// Syntax error with a single labelled location.
+	pub fn syntax(location: Location, message: &str) -> Self {
+		let error = SyntaxError {
+			location: location.clone(),
+			message: message.into()
+		};
+		Error::new_at(ErrorKind::Syntax(vec![error]), location)
+	}
+
+	pub fn kind(&self) -> &ErrorKind {
+		&self.kind
+	}
+
+	pub fn location(&self) -> Option<&Location> {
+		self.location.as_ref()
+	}
+}
+
+impl Error {
+	/// Every location the report points at, with the message attached to it.
+	fn labels(&self) -> Vec<(&Location, String)> {
+		match &self.kind {
+			ErrorKind::Syntax(errors) if !errors.is_empty() => errors.iter()
+				.map(|err| (&err.location, err.message.clone()))
+				.collect(),
+			ErrorKind::ArityMismatch { defined_at: Some(definition), .. } => self.location.iter()
+				.map(|loc| (loc, self.kind.to_string()))
+				.chain([(definition, "Function defined here".to_string())])
+				.collect(),
+			_ => self.location.iter()
+				.map(|loc| (loc, self.kind.to_string()))
+				.collect()
+		}
+	}
+}
+
+/// ariadne can't point into text that doesn't exist (e.g. calls made by the host)
+fn renderable(location: &Location) -> bool {
+	let text = location.source.text();
+	!text.is_empty() && location.span.end <= text.len()
 }
 
 impl Display for Error {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		use ErrorKind::*;
-		match &self.kind {
-			File(path) => write!(f, "Can't open file `{path}`"),
-			Usage => write!(f, "Usage: ab_lisp <path>"),
-			Syntax => {
-				let location = self.location.as_ref().unwrap();
-				let rep = ariadne::Report::build(ariadne::ReportKind::Error, (), location.span.start())
-					.with_label(Label::new(location.span.clone()))
-					.finish();
-				let mut buf: Vec<u8> = Vec::new();
-				let src = match &location.source {
-					location::Source::File(path) => std::fs::read_to_string(path).unwrap()
-				};
-				rep.write(Source::from(src), &mut buf).unwrap();
-				write!(f, "{}", String::from_utf8(buf).unwrap())
-			}
-			NotAFunction => match &self.location {
-				Some(loc) => {
-					let mut buf: Vec<u8> = Vec::new();
-					let src = match &loc.source {
-						location::Source::File(path) => std::fs::read_to_string(path).unwrap()
-					};
-					ariadne::Report::build(ariadne::ReportKind::Error, (), loc.span.start())
-						.with_label(Label::new(loc.span.clone()).with_message("Attempt to call a non-function value"))
-						.finish()
-						.write(Source::from(src), &mut buf).unwrap();
-					write!(f, "{}", String::from_utf8(buf).unwrap())
-				}
-				None => write!(f, "Attempt to call a non-function value")
-			}
-			BadIndex => match &self.location {
-				Some(loc) => {
-					let mut buf: Vec<u8> = Vec::new();
-					let src = match &loc.source {
-						location::Source::File(path) => std::fs::read_to_string(path).unwrap()
-					};
-					ariadne::Report::build(ariadne::ReportKind::Error, (), loc.span.start())
-						.with_label(Label::new(loc.span.clone()).with_message("Attempt to index an object with non-atom value"))
-						.finish()
-						.write(Source::from(src), &mut buf).unwrap();
-					write!(f, "{}", String::from_utf8(buf).unwrap())
-				}
-				None => write!(f, "Attempt to index an object with non-atom value")
-			}
+		let labels = self.labels();
+		if labels.is_empty() {
+			return write!(f, "{}", self.kind);
+		}
+		let several = labels.len() > 1;
+		let (labels, notes): (Vec<_>, Vec<_>) = labels.into_iter().partition(|(loc, _)| renderable(loc));
+		let notes = notes.iter()
+			.map(|(loc, message)| format!("{}: {message}", loc.source))
+			.collect::<Vec<_>>();
+		if labels.is_empty() {
+			return notes.iter().try_for_each(|note| writeln!(f, "{note}"));
+		}
+
+		let (first, _) = labels[0];
+		let mut report = ariadne::Report::build(ariadne::ReportKind::Error, first.source.to_string(), first.span.start);
+		if several {
+			report.set_message(&self.kind);
 		}
+		if !notes.is_empty() {
+			report.set_note(notes.join("\n"));
+		}
+		let mut sources = Vec::new();
+		for (loc, message) in labels {
+			let name = loc.source.to_string();
+			report.add_label(Label::new((name.clone(), loc.span.clone())).with_message(message));
+			sources.push((name, loc.source.text()));
+		}
+		let mut buf: Vec<u8> = Vec::new();
+		report.finish()
+			.write(ariadne::sources(sources), &mut buf)
+			.map_err(|_| fmt::Error)?;
+		write!(f, "{}", String::from_utf8_lossy(&buf))
 	}
 }
 
 impl std::error::Error for Error {}
 
+#[derive(Debug, Clone)]
+pub struct SyntaxError {
+	pub location: Location,
+	pub message: String
+}
+
 #[derive(Debug)]
 pub enum ErrorKind {
 	File(String),
+	Io(std::io::Error),
 	Usage,
-	Syntax,
+	/// Every problem found while reading a piece of code
+	Syntax(Vec<SyntaxError>),
 	NotAFunction,
-	BadIndex
+	BadIndex,
+	/// Read or `set` of a variable that was never defined
+	UndefinedVariable {
+		name: String,
+		/// Similar name that is defined, probably meant instead
+		suggestion: Option<String>
+	},
+	ArityMismatch {
+		expected: Arity,
+		given: usize,
+		/// Definition of the called function, unless it's native
+		defined_at: Option<Location>
+	},
+	TypeMismatch {
+		expected: &'static str,
+		found: &'static str
+	},
+	/// Atom passed as a keyword argument that the function doesn't take
+	UnknownKeyword(String),
+	/// Keyword passed as the last argument, without a value
+	MissingKeywordValue(String),
+	/// Error reported by a host function
+	Native(String),
+	LimitExceeded(Limit),
+	/// Evaluation was cancelled through an [`InterruptHandle`](crate::state::InterruptHandle)
+	Interrupted
+}
+
+impl Display for ErrorKind {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		use ErrorKind::*;
+		match self {
+			File(path) => write!(f, "Can't open file `{path}`"),
+			Io(err) => write!(f, "IO error: {err}"),
+			Usage => write!(f, "Usage: ab_lisp <path | ->"),
+			Syntax(errors) if errors.len() == 1 => write!(f, "{}", errors[0].message),
+			Syntax(errors) => write!(f, "{} syntax errors", errors.len()),
+			NotAFunction => write!(f, "Attempt to call a non-function value"),
+			BadIndex => write!(f, "Attempt to index an object with non-atom value"),
+			UndefinedVariable { name, suggestion: Some(suggestion) } => write!(f, "Undefined variable `{name}`, did you mean `{suggestion}`?"),
+			UndefinedVariable { name, suggestion: None } => write!(f, "Undefined variable `{name}`"),
+			ArityMismatch { expected, given, .. } => write!(f, "Expected {expected} arguments, got {given}"),
+			TypeMismatch { expected, found } => write!(f, "Expected {expected}, got {found}"),
+			UnknownKeyword(name) => write!(f, "Unknown keyword argument `'{name}`"),
+			MissingKeywordValue(name) => write!(f, "Missing value for keyword argument `'{name}`"),
+			Native(msg) => write!(f, "{msg}"),
+			LimitExceeded(Limit::Steps) => write!(f, "Evaluation step limit exceeded"),
+			LimitExceeded(Limit::Depth) => write!(f, "Maximum call depth exceeded"),
+			LimitExceeded(Limit::Nesting) => write!(f, "Maximum nesting of native calls into Lisp exceeded"),
+			LimitExceeded(Limit::Expansion) => write!(f, "Maximum macro expansion depth exceeded"),
+			LimitExceeded(Limit::CodeDepth) => write!(f, "Maximum nesting of code built from data exceeded"),
+			LimitExceeded(Limit::ListLength) => write!(f, "Maximum list length exceeded"),
+			LimitExceeded(Limit::StringLength) => write!(f, "Maximum string length exceeded"),
+			Interrupted => write!(f, "Interrupted")
+		}
+	}
+}
+
+/// Picks the candidate closest to `name`, if any is close enough to be a typo.
+/// Ties go to the earliest candidate, so callers list inner scopes first.
+/// Replacing every character of `name` doesn't count as a typo.
+pub(crate) fn closest_name<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<String> {
+	let len = name.chars().count();
+	let max_distance = (len / 3).max(1);
+	candidates
+		.filter(|candidate| *candidate != name)
+		.map(|candidate| (edit_distance(name, candidate), candidate))
+		.filter(|(distance, _)| *distance <= max_distance && *distance < len)
+		.min_by_key(|(distance, _)| *distance)
+		.map(|(_, candidate)| candidate.to_string())
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+	let b = b.chars().collect::<Vec<_>>();
+	let mut prev = (0..=b.len()).collect::<Vec<_>>();
+	for (i, ca) in a.chars().enumerate() {
+		let mut cur = vec![i + 1; b.len() + 1];
+		for (j, cb) in b.iter().enumerate() {
+			let substitution = prev[j] + usize::from(ca != *cb);
+			cur[j + 1] = substitution.min(prev[j + 1] + 1).min(cur[j] + 1);
+		}
+		prev = cur;
+	}
+	prev[b.len()]
 }
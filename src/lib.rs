@@ -0,0 +1,30 @@
+//! Embeddable interpreter for the ab_lisp language.
+//!
+//! The usual way to run a script from Rust is to create a [`State`], load the
+//! prelude and evaluate some source:
+//!
+//! ```
+//! use ab_lisp::{State, Value};
+//!
+//! let mut abl = State::new();
+//! abl.reg_prelude();
+//! abl.set_global("x", Value::Number(20.into()));
+//! let res = abl.eval_str("(+ x 22)").unwrap();
+//! assert_eq!(res, Value::Number(42.into()));
+//! ```
+
+pub mod action;
+mod bytecode;
+mod compiler;
+pub mod error;
+pub mod io;
+pub mod location;
+mod macros;
+pub mod parser;
+mod prelude;
+pub mod state;
+pub mod value;
+
+pub use error::{Error, ErrorKind};
+pub use state::State;
+pub use value::Value;
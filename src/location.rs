@@ -1,12 +1,82 @@
+use std::{rc::Rc, fmt::{self, Display}};
+
 use logos::Span;
 
 #[derive(Debug, Clone)]
 pub struct Location {
-	pub source: Source,
+	pub source: Rc<Source>,
 	pub span: Span
 }
 
-#[derive(Debug, Clone)]
+impl Location {
+	/// Location used for calls made by the host rather than by a script.
+	pub fn host() -> Self {
+		Location {
+			source: Rc::new(Source::string("<host>", "")),
+			span: 0..0
+		}
+	}
+
+	pub fn is_host(&self) -> bool {
+		matches!(&*self.source, Source::String { name, text } if name == "<host>" && text.is_empty())
+	}
+}
+
+/// Origin of a piece of code. Every variant keeps the full text so diagnostics
+/// can be rendered without going back to the file system.
+#[derive(Debug)]
 pub enum Source {
-	File(String)
+	File {
+		path: String,
+		text: String
+	},
+	String {
+		name: String,
+		text: String
+	},
+	Stdin(String),
+	Repl {
+		line: usize,
+		text: String
+	}
+}
+
+impl Source {
+	pub fn file(path: &str) -> std::io::Result<Self> {
+		let text = std::fs::read_to_string(path)?;
+		Ok(Source::File { path: path.into(), text })
+	}
+
+	pub fn string(name: &str, text: &str) -> Self {
+		Source::String { name: name.into(), text: text.into() }
+	}
+
+	pub fn stdin() -> std::io::Result<Self> {
+		let text = std::io::read_to_string(std::io::stdin())?;
+		Ok(Source::Stdin(text))
+	}
+
+	pub fn repl(line: usize, text: &str) -> Self {
+		Source::Repl { line, text: text.into() }
+	}
+
+	pub fn text(&self) -> &str {
+		match self {
+			Source::File { text, .. } => text,
+			Source::String { text, .. } => text,
+			Source::Stdin(text) => text,
+			Source::Repl { text, .. } => text
+		}
+	}
+}
+
+impl Display for Source {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Source::File { path, .. } => write!(f, "{path}"),
+			Source::String { name, .. } => write!(f, "{name}"),
+			Source::Stdin(_) => write!(f, "<stdin>"),
+			Source::Repl { line, .. } => write!(f, "<repl:{line}>")
+		}
+	}
 }
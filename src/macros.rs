@@ -0,0 +1,351 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{action::{Action, ActionVal}, error::Error, location::Location, value::function::Function};
+
+pub(crate) enum Macro {
+	/// Defined with `defmacro`: a function from forms (as data) to a form
+	Procedural(Rc<Function>),
+	/// Defined with `define-syntax`
+	Rules(Rc<SyntaxRules>)
+}
+
+const ELLIPSIS: &str = "...";
+
+/// Pattern based macro in the style of `syntax-rules`. Patterns are matched
+/// against the `Action` tree of a call, so code taken from the call keeps its
+/// own spans while code introduced by a template points at the call site.
+///
+/// Identifiers a template binds (with `let`, `function`, `funcap` or `lambda`) are
+/// renamed on every expansion, so they can't capture the caller's variables.
+pub(crate) struct SyntaxRules {
+	name: String,
+	literals: Vec<String>,
+	rules: Vec<(Vec<Action>, Action)>
+}
+
+#[derive(Clone)]
+enum Binding {
+	One(Action),
+	/// Matched under an ellipsis, one entry per repetition
+	Many(Vec<Binding>)
+}
+
+type Bindings = HashMap<String, Binding>;
+
+fn is_ellipsis(action: &Action) -> bool {
+	matches!(&action.val, ActionVal::Ident(name) if name == ELLIPSIS)
+}
+
+impl SyntaxRules {
+	/// Builds the macro from the `(syntax-rules (literals...) (pattern template)...)` form.
+	pub fn new(name: &str, spec: &Action) -> Result<Self, Error> {
+		let content = match &spec.val {
+			ActionVal::Group { content, quoted: false } => content,
+			_ => return Err(Error::syntax(spec.location.clone(), "Expected a `syntax-rules` form"))
+		};
+		match content.first().map(|head| &head.val) {
+			Some(ActionVal::Ident(head)) if head == "syntax-rules" => (),
+			_ => return Err(Error::syntax(spec.location.clone(), "Expected a `syntax-rules` form"))
+		}
+
+		let literals = match content.get(1).map(|lits| &lits.val) {
+			Some(ActionVal::Group { content, quoted: false }) => content,
+			_ => return Err(Error::syntax(spec.location.clone(), "Expected a list of literals"))
+		};
+		let literals = literals.iter()
+			.map(|lit| match &lit.val {
+				ActionVal::Ident(name) => Ok(name.clone()),
+				_ => Err(Error::syntax(lit.location.clone(), "Expected an identifier"))
+			})
+			.collect::<Result<_, _>>()?;
+
+		let mut rules = Vec::new();
+		for rule in &content[2..] {
+			match &rule.val {
+				ActionVal::Group { content, quoted: false } if content.len() == 2 => match &content[0].val {
+					// the first element of a pattern stands for the macro keyword
+					ActionVal::Group { content: pattern, quoted: false } if !pattern.is_empty() => {
+						rules.push((pattern[1..].to_vec(), content[1].clone()));
+					}
+					_ => return Err(Error::syntax(content[0].location.clone(), "Expected a pattern"))
+				}
+				_ => return Err(Error::syntax(rule.location.clone(), "Expected a pattern and a template"))
+			}
+		}
+
+		let res = SyntaxRules {
+			name: name.into(),
+			literals,
+			rules
+		};
+		for (pattern, _) in &res.rules {
+			let mut vars = Vec::new();
+			for item in pattern {
+				res.pattern_vars(item, &mut vars);
+			}
+			for (i, (name, location)) in vars.iter().enumerate() {
+				if vars[..i].iter().any(|(prev, _)| prev == name) {
+					return Err(Error::syntax((*location).clone(), &format!("Pattern variable `{name}` appears twice")));
+				}
+			}
+		}
+		Ok(res)
+	}
+
+	/// Expands the call `form` with the first matching rule. `gensym` numbers
+	/// the renamed bindings and is advanced past every name used.
+	pub fn expand(&self, form: &Action, gensym: &mut usize) -> Result<Action, Error> {
+		let args = match &form.val {
+			ActionVal::Group { content, .. } => &content[1..],
+			_ => &[]
+		};
+		for (pattern, template) in &self.rules {
+			let mut bindings = Bindings::new();
+			if self.match_seq(pattern, args, &mut bindings) {
+				let mut renames = HashMap::new();
+				collect_binders(template, &bindings, &mut |name| {
+					renames.entry(name.to_string()).or_insert_with(|| {
+						*gensym += 1;
+						format!("{name}%{gensym}")
+					});
+				});
+				let mut res = expand_template(template, &bindings, &renames, &form.location)?;
+				return match res.len() {
+					1 => Ok(res.pop().unwrap()),
+					_ => Err(Error::syntax(template.location.clone(), "Template must produce a single form"))
+				};
+			}
+		}
+		Err(Error::syntax(form.location.clone(), &format!("No rule of `{}` matches this form", self.name)))
+	}
+
+	fn match_one(&self, pattern: &Action, form: &Action, bindings: &mut Bindings) -> bool {
+		match (&pattern.val, &form.val) {
+			(ActionVal::Ident(name), _) if name == "_" => true,
+			(ActionVal::Ident(name), ActionVal::Ident(other)) if self.literals.contains(name) => name == other,
+			(ActionVal::Ident(name), _) if self.literals.contains(name) => false,
+			(ActionVal::Ident(name), _) => {
+				bindings.insert(name.clone(), Binding::One(form.clone()));
+				true
+			}
+			(ActionVal::Literal(val), ActionVal::Literal(other)) => val == other,
+			(ActionVal::Group { content, quoted }, ActionVal::Group { content: other, quoted: other_quoted }) => {
+				quoted == other_quoted && self.match_seq(content, other, bindings)
+			}
+			(ActionVal::Quasiquote(inner), ActionVal::Quasiquote(other))
+			| (ActionVal::Unquote(inner), ActionVal::Unquote(other))
+			| (ActionVal::UnquoteSplicing(inner), ActionVal::UnquoteSplicing(other)) => {
+				self.match_one(inner, other, bindings)
+			}
+			_ => false
+		}
+	}
+
+	fn match_seq(&self, patterns: &[Action], forms: &[Action], bindings: &mut Bindings) -> bool {
+		match patterns.iter().position(is_ellipsis) {
+			Some(idx) if idx > 0 => {
+				let before = &patterns[..idx - 1];
+				let repeated = &patterns[idx - 1];
+				let after = &patterns[idx + 1..];
+				if forms.len() < before.len() + after.len() {
+					return false;
+				}
+				let rest = forms.len() - after.len();
+				if !self.match_seq(before, &forms[..before.len()], bindings)
+					|| !self.match_seq(after, &forms[rest..], bindings) {
+					return false;
+				}
+
+				let mut matches = Vec::new();
+				for form in &forms[before.len()..rest] {
+					let mut inner = Bindings::new();
+					if !self.match_one(repeated, form, &mut inner) {
+						return false;
+					}
+					matches.push(inner);
+				}
+				let mut vars = Vec::new();
+				self.pattern_vars(repeated, &mut vars);
+				for (name, _) in vars {
+					let items = matches.iter_mut()
+						.map(|inner| inner.remove(name))
+						.collect::<Option<_>>();
+					match items {
+						Some(items) => bindings.insert(name.into(), Binding::Many(items)),
+						None => return false
+					};
+				}
+				true
+			}
+			_ => patterns.len() == forms.len() && patterns.iter()
+				.zip(forms)
+				.all(|(pattern, form)| self.match_one(pattern, form, bindings))
+		}
+	}
+
+	/// Identifiers of `pattern` that bind parts of the form.
+	fn pattern_vars<'a>(&self, pattern: &'a Action, vars: &mut Vec<(&'a str, &'a Location)>) {
+		match &pattern.val {
+			ActionVal::Ident(name) if name == "_" || name == ELLIPSIS || self.literals.contains(name) => (),
+			ActionVal::Ident(name) => vars.push((name, &pattern.location)),
+			ActionVal::Literal(_) => (),
+			ActionVal::Group { content, .. } => {
+				for item in content.iter() {
+					self.pattern_vars(item, vars);
+				}
+			}
+			ActionVal::Quasiquote(inner) | ActionVal::Unquote(inner) | ActionVal::UnquoteSplicing(inner) => {
+				self.pattern_vars(inner, vars);
+			}
+		}
+	}
+}
+
+/// Calls `found` for every identifier the template itself binds.
+fn collect_binders(template: &Action, bindings: &Bindings, found: &mut impl FnMut(&str)) {
+	let mut binder = |action: &Action| match &action.val {
+		ActionVal::Ident(name) if !bindings.contains_key(name) && name != ELLIPSIS => found(name),
+		_ => ()
+	};
+	match &template.val {
+		ActionVal::Group { content, quoted: false } => {
+			match content.first().map(|head| &head.val) {
+				Some(ActionVal::Ident(head)) if head == "let" => {
+					content[1..].iter().step_by(2).for_each(&mut binder);
+				}
+				Some(ActionVal::Ident(head)) if head == "function" || head == "funcap" => {
+					if let Some(name) = content.get(1) {
+						binder(name);
+					}
+					if let Some(ActionVal::Group { content: args, .. }) = content.get(2).map(|args| &args.val) {
+						param_binders(args, &mut binder);
+					}
+				}
+				Some(ActionVal::Ident(head)) if head == "lambda" || head == "fn" => {
+					if let Some(ActionVal::Group { content: args, .. }) = content.get(1).map(|args| &args.val) {
+						param_binders(args, &mut binder);
+					}
+				}
+				_ => ()
+			}
+			for item in content.iter() {
+				collect_binders(item, bindings, found);
+			}
+		}
+		ActionVal::Group { content, quoted: true } => {
+			for item in content.iter() {
+				collect_binders(item, bindings, found);
+			}
+		}
+		ActionVal::Quasiquote(inner) | ActionVal::Unquote(inner) | ActionVal::UnquoteSplicing(inner) => {
+			collect_binders(inner, bindings, found);
+		}
+		ActionVal::Ident(_) | ActionVal::Literal(_) => ()
+	}
+}
+
+/// Calls `binder` for the parameters of a parameter list. Markers aren't
+/// variables, and keyword parameters keep their names since callers pass
+/// them as atoms.
+fn param_binders(args: &[Action], binder: &mut impl FnMut(&Action)) {
+	for arg in args {
+		match &arg.val {
+			ActionVal::Ident(name) if name == "&key" => break,
+			ActionVal::Ident(name) if name.starts_with('&') => (),
+			ActionVal::Group { content, quoted: false } if !content.is_empty() => binder(&content[0]),
+			_ => binder(arg)
+		}
+	}
+}
+
+fn expand_template(template: &Action, bindings: &Bindings, renames: &HashMap<String, String>, location: &Location) -> Result<Vec<Action>, Error> {
+	let introduced = |val| vec![Action { val, location: location.clone() }];
+	match &template.val {
+		ActionVal::Ident(name) => match bindings.get(name) {
+			Some(Binding::One(action)) => Ok(vec![action.clone()]),
+			Some(Binding::Many(_)) => Err(Error::syntax(template.location.clone(), "Pattern variable used without `...`")),
+			None => {
+				let name = renames.get(name).unwrap_or(name);
+				Ok(introduced(ActionVal::Ident(name.clone())))
+			}
+		}
+		ActionVal::Literal(val) => Ok(introduced(ActionVal::Literal(val.clone()))),
+		ActionVal::Group { content, quoted } => {
+			let mut res = Vec::with_capacity(content.len());
+			let mut idx = 0;
+			while idx < content.len() {
+				let item = &content[idx];
+				if content.get(idx + 1).is_some_and(is_ellipsis) {
+					for bindings in repetitions(item, bindings)? {
+						res.extend(expand_template(item, &bindings, renames, location)?);
+					}
+					idx += 2;
+				} else {
+					res.extend(expand_template(item, bindings, renames, location)?);
+					idx += 1;
+				}
+			}
+			Ok(introduced(ActionVal::Group { content: res.into(), quoted: *quoted }))
+		}
+		ActionVal::Quasiquote(inner) => Ok(introduced(ActionVal::Quasiquote(Box::new(expand_single(inner, bindings, renames, location)?)))),
+		ActionVal::Unquote(inner) => Ok(introduced(ActionVal::Unquote(Box::new(expand_single(inner, bindings, renames, location)?)))),
+		ActionVal::UnquoteSplicing(inner) => Ok(introduced(ActionVal::UnquoteSplicing(Box::new(expand_single(inner, bindings, renames, location)?))))
+	}
+}
+
+fn expand_single(template: &Action, bindings: &Bindings, renames: &HashMap<String, String>, location: &Location) -> Result<Action, Error> {
+	let mut res = expand_template(template, bindings, renames, location)?;
+	match res.len() {
+		1 => Ok(res.pop().unwrap()),
+		_ => Err(Error::syntax(template.location.clone(), "Expected a single form"))
+	}
+}
+
+/// Splits the bindings of a template followed by `...` into one set of
+/// bindings per repetition.
+fn repetitions(template: &Action, bindings: &Bindings) -> Result<Vec<Bindings>, Error> {
+	let mut names = Vec::new();
+	template_idents(template, &mut names);
+	let repeated = names.iter()
+		.filter_map(|name| match bindings.get(name) {
+			Some(Binding::Many(items)) => Some((name, items)),
+			_ => None
+		})
+		.collect::<Vec<_>>();
+	let count = match repeated.first() {
+		Some((_, items)) => items.len(),
+		None => return Err(Error::syntax(template.location.clone(), "No pattern variable to repeat with `...`"))
+	};
+	if repeated.iter().any(|(_, items)| items.len() != count) {
+		return Err(Error::syntax(template.location.clone(), "Pattern variables repeat a different number of times"));
+	}
+
+	let mut res = Vec::with_capacity(count);
+	for idx in 0..count {
+		let mut inner = Bindings::new();
+		for (name, binding) in bindings {
+			let binding = match binding {
+				Binding::Many(items) if repeated.iter().any(|(repeated, _)| *repeated == name) => items[idx].clone(),
+				other => other.clone()
+			};
+			inner.insert(name.clone(), binding);
+		}
+		res.push(inner);
+	}
+	Ok(res)
+}
+
+fn template_idents(template: &Action, names: &mut Vec<String>) {
+	match &template.val {
+		ActionVal::Ident(name) => names.push(name.clone()),
+		ActionVal::Literal(_) => (),
+		ActionVal::Group { content, .. } => {
+			for item in content.iter() {
+				template_idents(item, names);
+			}
+		}
+		ActionVal::Quasiquote(inner) | ActionVal::Unquote(inner) | ActionVal::UnquoteSplicing(inner) => {
+			template_idents(inner, names);
+		}
+	}
+}
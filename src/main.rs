@@ -1,24 +1,30 @@
-mod action;
-mod error;
-mod location;
-mod parser;
-mod prelude;
-mod state;
-mod value;
+use ab_lisp::{Error, ErrorKind, State, location::Source};
 
-fn run() -> Result<(), error::Error> {
+fn run() -> Result<(), Error> {
 	let args = std::env::args().collect::<Vec<_>>();
 	if args.len() == 2 {
-		let src = std::fs::read_to_string(&args[1])
-			.map_err(|_| error::Error::new(error::ErrorKind::File(args[1].clone())))?;
-		let mut abl = state::State::new();
-		let source = location::Source::File(args[1].clone());
-		let actions = parser::parse(source, &src)?;
+		let mut abl = State::new();
 		abl.reg_prelude();
-		abl.execute(&actions[..])?;
+		let source = if args[1] == "-" {
+			Source::stdin().map_err(|_| Error::new(ErrorKind::File("<stdin>".into())))?
+		} else {
+			Source::file(&args[1]).map_err(|_| Error::new(ErrorKind::File(args[1].clone())))?
+		};
+		// Installed once the source is read, so Ctrl-C still kills the process
+		// while it waits for stdin. A second Ctrl-C exits when the first one
+		// wasn't noticed, e.g. during a blocking `read-line`. Without a handler
+		// the process is simply killed, which is fine too.
+		let interrupt = abl.interrupt_handle();
+		let _ = ctrlc::set_handler(move || {
+			if interrupt.is_pending() {
+				std::process::exit(130);
+			}
+			interrupt.interrupt();
+		});
+		abl.eval_source(source)?;
 		Ok(())
 	} else {
-		Err(error::Error::new(error::ErrorKind::Usage))
+		Err(Error::new(ErrorKind::Usage))
 	}
 }
 
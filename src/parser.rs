@@ -1,70 +1,186 @@
-use logos::{Logos, Lexer};
+use std::rc::Rc;
 
-use crate::{action::{Action, ActionVal}, error::{Error, ErrorKind}, location::{self, Location}, value::{Value, number::Number}};
+use logos::{Logos, Lexer, Span};
+
+use crate::{action::{Action, ActionVal}, error::{Error, ErrorKind, SyntaxError}, location::{self, Location}, value::{Value, number::Number}};
 
 use self::token::Token;
 
 mod token;
 
-pub fn parse(source: location::Source, src: &str) -> Result<Vec<Action>, Error> {
-	let mut lex = token::Token::lexer(src);
-	let res = parse_rec(&source, &mut lex)?;
-	if lex.next().is_none() {
+/// Parses a whole source. The parser doesn't stop at the first problem: every
+/// syntax error in the source is reported in a single [`ErrorKind::Syntax`].
+pub fn parse(source: location::Source) -> Result<Vec<Action>, Error> {
+	let source = Rc::new(source);
+	let mut lex = token::Token::lexer(source.text());
+	let mut errors = Vec::new();
+	let mut res = Vec::new();
+	loop {
+		let (mut actions, end) = parse_rec(&source, &mut lex, &mut errors);
+		res.append(&mut actions);
+		match end {
+			// a stray `)` is skipped and parsing goes on
+			Some(span) => errors.push(SyntaxError {
+				location: Location { source: source.clone(), span },
+				message: "Unexpected `)`".into()
+			}),
+			None => break
+		}
+	}
+	if errors.is_empty() {
 		Ok(res)
 	} else {
-		Err(Error::new_at(ErrorKind::Syntax, res[res.len() - 1].location.clone()))
+		let location = errors[0].location.clone();
+		Err(Error::new_at(ErrorKind::Syntax(errors), location))
 	}
 }
 
-fn parse_rec(source: &location::Source, lex: &mut Lexer<Token>) -> Result<Vec<Action>, Error> {
+/// Result of reading one token and whatever it introduces.
+enum Item {
+	Action(Action),
+	/// Closing `)` of the enclosing group
+	GroupEnd(Span),
+	/// Nothing usable was read, the problem is already recorded
+	Skipped
+}
+
+/// Parses actions until the end of input or a `)`, whose span is returned
+/// so the caller can tell the two apart. Invalid tokens are recorded in
+/// `errors` and skipped, which keeps the group structure intact.
+fn parse_rec(source: &Rc<location::Source>, lex: &mut Lexer<Token>, errors: &mut Vec<SyntaxError>) -> (Vec<Action>, Option<Span>) {
 	let mut res = Vec::new();
 	while let Some(token) = lex.next() {
-		use token::Token::*;
-		let location = Location {
-			span: lex.span(),
-			source: source.clone()
-		};
-		let val = match token {
-			Ident => {
-				let val = lex.slice();
-				ActionVal::Ident(val.into())
-			}
-			Atom => {
-				let val = &lex.slice()[1..];
-				ActionVal::Literal(Value::Atom(val.into()))
-			}
-			Int => {
-				let val = lex.slice().parse().unwrap();
-				ActionVal::Literal(Value::Number(Number::Int(val)))
-			}
-			Float => {
-				let val = lex.slice().parse().unwrap();
-				ActionVal::Literal(Value::Number(Number::Float(val)))
+		match parse_item(source, lex, errors, token) {
+			Item::Action(action) => res.push(action),
+			Item::GroupEnd(span) => return (res, Some(span)),
+			Item::Skipped => ()
+		}
+	}
+	(res, None)
+}
+
+fn parse_item(source: &Rc<location::Source>, lex: &mut Lexer<Token>, errors: &mut Vec<SyntaxError>, token: Token) -> Item {
+	use token::Token::*;
+	let location = Location {
+		span: lex.span(),
+		source: source.clone()
+	};
+	let val = match token {
+		Ident => {
+			let val = lex.slice();
+			ActionVal::Ident(val.into())
+		}
+		Atom => {
+			let val = &lex.slice()[1..];
+			ActionVal::Literal(Value::Atom(val.into()))
+		}
+		Int => match lex.slice().parse() {
+			Ok(val) => ActionVal::Literal(Value::Number(Number::Int(val))),
+			Err(_) => return skip(errors, location, "Integer literal out of range".into())
+		}
+		Float => match lex.slice().parse() {
+			Ok(val) => ActionVal::Literal(Value::Number(Number::Float(val))),
+			Err(_) => return skip(errors, location, format!("Invalid number `{}`", lex.slice()))
+		}
+		String => match enquote::unquote(lex.slice()) {
+			Ok(val) => ActionVal::Literal(Value::String(val)),
+			Err(_) => return skip(errors, location, "Invalid escape sequence in string".into())
+		}
+		GroupStart => {
+			let content = parse_group(source, lex, errors, "(", &location);
+			ActionVal::Group {
+				content: content.into(), quoted: false
 			}
-			String => {
-				let val = enquote::unquote(lex.slice()).unwrap();
-				ActionVal::Literal(Value::String(val))
+		}
+		ListStart => {
+			let content = parse_group(source, lex, errors, "'(", &location);
+			ActionVal::Group {
+				content: content.into(), quoted: true
 			}
-			GroupStart => {
-				let content = parse_rec(source, lex)?;
-				ActionVal::Group {
-					content, quoted: false
+		}
+		Quasiquote | Unquote | UnquoteSplicing => {
+			let prefix = lex.slice();
+			let inner = match lex.next() {
+				Some(next) => parse_item(source, lex, errors, next),
+				None => Item::Skipped
+			};
+			let inner = match inner {
+				Item::Action(inner) => Box::new(inner),
+				other => {
+					errors.push(SyntaxError {
+						location,
+						message: format!("Expected an expression after '{prefix}'")
+					});
+					return other;
 				}
+			};
+			match token {
+				Quasiquote => ActionVal::Quasiquote(inner),
+				Unquote => ActionVal::Unquote(inner),
+				_ => ActionVal::UnquoteSplicing(inner)
 			}
-			ListStart => {
-				let content = parse_rec(source, lex)?;
-				ActionVal::Group {
-					content, quoted: true
-				}
-			}
-			GroupEnd => {
-				return Ok(res);
-			}
-			Error => {
-				return Err(crate::error::Error::new_at(ErrorKind::Syntax, location));
-			}
-		};
-		res.push(Action { location, val });
+		}
+		GroupEnd => {
+			return Item::GroupEnd(location.span);
+		}
+		Error => {
+			return skip(errors, location, format!("Unexpected `{}`", lex.slice()));
+		}
+	};
+	Item::Action(Action { location, val })
+}
+
+/// Records a token that can't be read.
+fn skip(errors: &mut Vec<SyntaxError>, location: Location, message: String) -> Item {
+	errors.push(SyntaxError { location, message });
+	Item::Skipped
+}
+
+fn parse_group(source: &Rc<location::Source>, lex: &mut Lexer<Token>, errors: &mut Vec<SyntaxError>, opener: &str, location: &Location) -> Vec<Action> {
+	let (content, end) = parse_rec(source, lex, errors);
+	if end.is_none() {
+		errors.push(SyntaxError {
+			location: location.clone(),
+			message: format!("Unclosed `{opener}` opened here")
+		});
+	}
+	content
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{error::ErrorKind, location::Source};
+
+	fn errors(src: &str) -> Vec<String> {
+		match super::parse(Source::string("<test>", src)) {
+			Err(err) => match err.kind() {
+				ErrorKind::Syntax(errors) => errors.iter().map(|err| err.message.clone()).collect(),
+				other => panic!("unexpected error {}", other)
+			},
+			Ok(_) => Vec::new()
+		}
+	}
+
+	#[test]
+	fn unbalanced_parentheses_are_reported() {
+		assert_eq!(errors("(a (b)"), ["Unclosed `(` opened here"]);
+		assert_eq!(errors("(a) )"), ["Unexpected `)`"]);
+		assert!(errors("(a '(b))").is_empty());
+	}
+
+	#[test]
+	fn every_syntax_error_is_reported() {
+		assert_eq!(errors("(a 99999999999999999999) \"\\0\" ) (b @)"), [
+			"Integer literal out of range",
+			"Invalid escape sequence in string",
+			"Unexpected `)`",
+			"Unexpected `@`"
+		]);
+	}
+
+	#[test]
+	fn quasiquote_prefixes_need_an_expression() {
+		assert!(errors("`(c ,d ,@e)").is_empty());
+		assert_eq!(errors("(b ,)"), ["Expected an expression after ','"]);
 	}
-	Ok(res)
 }
@@ -2,8 +2,9 @@ use logos::Logos;
 
 #[derive(Logos, Debug)]
 pub enum Token {
-	#[regex("[a-zA-z_+*/=<>!][a-zA-Z0-9_+*/=<>!-]*")]
+	#[regex("[a-zA-Z_+*/=<>!&][a-zA-Z0-9_+*/=<>!-]*")]
 	#[token("-")]
+	#[token("...")]
 	Ident,
 
 	#[regex("'[a-zA-Z0-9_-]+")]
@@ -28,6 +29,15 @@ pub enum Token {
 	#[token("'(")]
 	ListStart,
 
+	#[token("`")]
+	Quasiquote,
+
+	#[token(",")]
+	Unquote,
+
+	#[token(",@")]
+	UnquoteSplicing,
+
 	#[error]
 	#[regex("\\s", logos::skip)]
 	#[regex(";.*\n", logos::skip)]
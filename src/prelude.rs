@@ -1,21 +1,34 @@
-use crate::{state::State, value::{Value, number::Number, list::List}, error::Error};
+use crate::{action::Action, location::{Location, Source}, parser, state::State, value::{Value, number::Number, list::List}, error::{Error, ErrorKind}};
 
-pub fn debug(_abl: &mut State, args: &[Value]) -> Result<Value, Error> {
+pub fn debug(abl: &mut State, args: &[Value]) -> Result<Value, Error> {
 	for val in args {
-		println!("{val:?}");
+		writeln!(abl.output(), "{val:?}").map_err(|err| Error::new(ErrorKind::Io(err)))?;
 	}
 	Ok(Value::nil())
 }
 
-pub fn print(_abl: &mut State, args: &[Value]) -> Result<Value, Error> {
+pub fn print(abl: &mut State, args: &[Value]) -> Result<Value, Error> {
 	for arg in args {
-		print!("{}", arg.to_string());
+		write!(abl.output(), "{arg}").map_err(|err| Error::new(ErrorKind::Io(err)))?;
 	}
 	Ok(Value::nil())
 }
 
+pub fn read_line(abl: &mut State, _args: &[Value]) -> Result<Value, Error> {
+	let mut line = String::new();
+	let read = abl.input().read_line(&mut line).map_err(|err| Error::new(ErrorKind::Io(err)))?;
+	if read == 0 {
+		Ok(Value::nil())
+	} else {
+		if line.ends_with('\n') {
+			line.pop();
+		}
+		Ok(Value::String(line))
+	}
+}
+
 pub fn head(_abl: &mut State, args: &[Value]) -> Result<Value, Error> {
-	if args.len() == 0 {
+	if args.is_empty() {
 		Ok(Value::nil())
 	} else {
 		match &args[0] {
@@ -26,7 +39,7 @@ pub fn head(_abl: &mut State, args: &[Value]) -> Result<Value, Error> {
 }
 
 pub fn tail(_abl: &mut State, args: &[Value]) -> Result<Value, Error> {
-	if args.len() == 0 {
+	if args.is_empty() {
 		Ok(Value::nil())
 	} else {
 		match &args[0] {
@@ -37,20 +50,60 @@ pub fn tail(_abl: &mut State, args: &[Value]) -> Result<Value, Error> {
 }
 
 pub fn cons(_abl: &mut State, args: &[Value]) -> Result<Value, Error> {
-	if args.len() == 0 {
+	if args.is_empty() {
 		Ok(Value::nil())
 	} else {
 		let mut list = match &args[args.len() - 1] {
 			Value::List(list) => list.clone(),
 			other => List::new().push_front(other.clone())
 		};
-		for item in args[..args.len() - 1].into_iter().rev() {
+		for item in args[..args.len() - 1].iter().rev() {
 			list = list.push_front(item.clone());
 		}
 		Ok(Value::List(list))
 	}
 }
 
+pub fn list(_abl: &mut State, args: &[Value]) -> Result<Value, Error> {
+	Ok(Value::list(args))
+}
+
+/// Reads the first expression of a string as data.
+pub fn read_string(_abl: &mut State, args: &[Value]) -> Result<Value, Error> {
+	match args.first() {
+		Some(Value::String(src)) => {
+			let actions = parser::parse(Source::string("<read-string>", src))?;
+			Ok(actions.first().map_or_else(Value::nil, Action::to_value))
+		}
+		other => Err(Error::new(ErrorKind::TypeMismatch {
+			expected: "string",
+			found: other.map_or("nothing", Value::type_name)
+		}))
+	}
+}
+
+/// `eval` passed around as a value. Unlike a direct `(eval ...)`, which is
+/// compiled in place and sees local variables, it runs the code in global scope.
+pub fn eval(abl: &mut State, args: &[Value]) -> Result<Value, Error> {
+	let code = args.first().cloned().unwrap_or_else(Value::nil);
+	let code = abl.code_from_value(&code, &Location::host())?;
+	abl.execute(&[code])
+}
+
+pub fn macroexpand_1(abl: &mut State, args: &[Value]) -> Result<Value, Error> {
+	let form = args.first().cloned().unwrap_or_else(Value::nil);
+	let expanded = abl.expand_macro(&form, &Location::host())?;
+	Ok(expanded.unwrap_or(form))
+}
+
+pub fn macroexpand(abl: &mut State, args: &[Value]) -> Result<Value, Error> {
+	let mut form = args.first().cloned().unwrap_or_else(Value::nil);
+	while let Some(expanded) = abl.expand_macro(&form, &Location::host())? {
+		form = expanded;
+	}
+	Ok(form)
+}
+
 pub fn add(_abl: &mut State, args: &[Value]) -> Result<Value, Error> {
 	let mut sum = Number::Int(0);
 	for arg in args {
@@ -62,7 +115,7 @@ pub fn add(_abl: &mut State, args: &[Value]) -> Result<Value, Error> {
 pub fn sub(_abl: &mut State, args: &[Value]) -> Result<Value, Error> {
 	if args.len() == 1 {
 		Ok(Value::Number(Number::Int(0) - args[0].to_number()))
-	} else if args.len() > 0 {
+	} else if !args.is_empty() {
 		let mut res = args[0].to_number();
 		for arg in &args[1..] {
 			res -= arg.to_number();
@@ -82,7 +135,7 @@ pub fn mul(_abl: &mut State, args: &[Value]) -> Result<Value, Error> {
 }
 
 pub fn div(_abl: &mut State, args: &[Value]) -> Result<Value, Error> {
-	if args.len() > 0 {
+	if !args.is_empty() {
 		let mut res = args[0].to_number();
 		for arg in &args[1..] {
 			res /= arg.to_number();
@@ -93,20 +146,8 @@ pub fn div(_abl: &mut State, args: &[Value]) -> Result<Value, Error> {
 	}
 }
 
-pub fn sqrt(_abl: &mut State, args: &[Value]) -> Result<Value, Error> {
-	if args.len() > 0 {
-		let x = match args[0].to_number() {
-			Number::Int(x) => x as f64,
-			Number::Float(x) => x
-		};
-		Ok(Value::Number(Number::Float(x.sqrt())))
-	} else {
-		Ok(Value::Number(Number::Float(1.)))
-	}
-}
-
 pub fn eq(_abl: &mut State, args: &[Value]) -> Result<Value, Error> {
-	if args.len() == 0 {
+	if args.is_empty() {
 		Ok(Value::Number(1.into()))
 	} else {
 		let val = &args[0];
@@ -120,7 +161,7 @@ pub fn eq(_abl: &mut State, args: &[Value]) -> Result<Value, Error> {
 }
 
 pub fn ne(_abl: &mut State, args: &[Value]) -> Result<Value, Error> {
-	if args.len() == 0 {
+	if args.is_empty() {
 		Ok(Value::Number(0.into()))
 	} else {
 		let val = &args[0];
@@ -164,3 +205,21 @@ pub fn ge(_abl: &mut State, args: &[Value]) -> Result<Value, Error> {
 		_ => Ok(Value::Number( (if args[0].to_number() >= args[1].to_number() { 1 } else { 0 }).into() ))
 	}
 }
+
+pub fn getenv(name: String) -> Option<String> {
+	std::env::var(name).ok()
+}
+
+pub fn time() -> f64 {
+	std::time::SystemTime::now()
+		.duration_since(std::time::UNIX_EPOCH)
+		.map_or(0., |time| time.as_secs_f64())
+}
+
+pub fn read_file(path: String) -> Result<String, std::io::Error> {
+	std::fs::read_to_string(path)
+}
+
+pub fn write_file(path: String, contents: String) -> Result<(), std::io::Error> {
+	std::fs::write(path, contents)
+}
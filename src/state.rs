@@ -1,270 +1,544 @@
-use std::{collections::{HashMap, LinkedList}, rc::Rc, cell::RefCell};
+use std::{collections::HashMap, rc::Rc, cell::RefCell, sync::{Arc, atomic::{AtomicBool, Ordering}}, io::{self, Write, BufRead, BufReader}};
 
-use crate::{prelude, value::{Value, function::Function, list::List}, action::{Action, ActionVal}, error::{Error, ErrorKind}};
+use crate::{prelude, value::{Value, function::{Function, FunctionVal}, list::List, convert::TypedFunction}, action::{Action, ActionVal}, error::{self, Error, ErrorKind}, location::{Location, Source}, parser, macros::Macro};
+
+mod vm;
+#[cfg(test)]
+mod tests;
+
+/// Resource limits for scripts that can't be trusted to terminate on their own.
+/// `None` disables a limit.
+#[derive(Debug, Clone)]
+pub struct Limits {
+	/// Maximum number of executed instructions per evaluation started by the host
+	pub max_steps: Option<u64>,
+	/// Maximum nesting of function calls
+	pub max_depth: Option<usize>,
+	/// Maximum nesting of evaluations started while another one runs, by
+	/// native functions calling back into Lisp or by procedural macros.
+	/// Unlike Lisp calls these use the native stack, so disabling this lets
+	/// such recursion crash the process.
+	pub max_nesting: Option<usize>,
+	/// Maximum nesting of macro expansions, i.e. macros expanding to calls
+	/// of macros. Expansion runs on the native stack, so disabling this lets
+	/// a recursive macro crash the process.
+	pub max_expansion_depth: Option<usize>,
+	/// Maximum nesting of lists turned into code by `eval` or procedural
+	/// macros. Compiling runs on the native stack like expansion does.
+	pub max_code_depth: Option<usize>,
+	/// Maximum length of a list built by a script
+	pub max_list_len: Option<usize>,
+	/// Maximum length in bytes of a string built by a script
+	pub max_string_len: Option<usize>
+}
+
+impl Default for Limits {
+	/// Only depths are limited by default, to keep runaway recursion from
+	/// eating all memory or overflowing the native stack.
+	fn default() -> Self {
+		Limits {
+			max_steps: None,
+			max_depth: Some(100_000),
+			max_nesting: Some(64),
+			max_expansion_depth: Some(64),
+			max_code_depth: Some(256),
+			max_list_len: None,
+			max_string_len: None
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+	Steps,
+	Depth,
+	Nesting,
+	Expansion,
+	CodeDepth,
+	ListLength,
+	StringLength
+}
+
+/// Handle that can stop a running evaluation from another thread. The state
+/// gives up with [`ErrorKind::Interrupted`] the next time it evaluates an
+/// expression or loops. Interrupts arriving while nothing runs are dropped
+/// when the next evaluation starts.
+#[derive(Debug, Clone)]
+pub struct InterruptHandle {
+	flag: Arc<AtomicBool>
+}
+
+impl InterruptHandle {
+	pub fn interrupt(&self) {
+		self.flag.store(true, Ordering::Relaxed);
+	}
+
+	/// Whether an interrupt was requested and not noticed yet, e.g. because
+	/// the script is blocked reading input.
+	pub fn is_pending(&self) -> bool {
+		self.flag.load(Ordering::Relaxed)
+	}
+}
+
+/// Group of builtins that can be enabled independently, so a sandboxed script
+/// only gets the capabilities the host is willing to grant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Module {
+	/// List operations and equality
+	Core,
+	/// Arithmetic and comparisons
+	Math,
+	/// Reading and writing the state's streams
+	Io,
+	/// `getenv`, `time`, `read-file` and `write-file`: the process and file
+	/// system access a sandbox withholds. Not part of [`Module::DEFAULT`].
+	Os
+}
+
+impl Module {
+	pub const ALL: [Module; 4] = [Module::Core, Module::Math, Module::Io, Module::Os];
+	/// Modules that only touch the state and its streams
+	pub const DEFAULT: [Module; 3] = [Module::Core, Module::Math, Module::Io];
+}
+
+/// Configures a [`State`] before creation. Nothing is registered unless asked for:
+///
+/// ```
+/// use ab_lisp::state::{State, Module};
+///
+/// let mut abl = State::builder()
+///     .module(Module::Core)
+///     .module(Module::Math)
+///     .build();
+/// assert!(abl.eval_str("(+ 1 2)").is_ok());
+/// assert!(abl.eval_str("(read-file \"/etc/passwd\")").is_err());
+/// ```
+#[derive(Default)]
+pub struct StateBuilder {
+	modules: Vec<Module>,
+	limits: Limits,
+	output: Option<Box<dyn Write>>,
+	input: Option<Box<dyn BufRead>>,
+	lax_variables: bool
+}
+
+impl StateBuilder {
+	pub fn module(mut self, module: Module) -> Self {
+		if !self.modules.contains(&module) {
+			self.modules.push(module);
+		}
+		self
+	}
+
+	pub fn modules(self, modules: &[Module]) -> Self {
+		modules.iter().fold(self, |builder, module| builder.module(*module))
+	}
+
+	pub fn limits(mut self, limits: Limits) -> Self {
+		self.limits = limits;
+		self
+	}
+
+	pub fn output<W: Write + 'static>(mut self, output: W) -> Self {
+		self.output = Some(Box::new(output));
+		self
+	}
+
+	pub fn input<R: BufRead + 'static>(mut self, input: R) -> Self {
+		self.input = Some(Box::new(input));
+		self
+	}
+
+	/// See [`State::set_lax_variables`].
+	pub fn lax_variables(mut self, lax: bool) -> Self {
+		self.lax_variables = lax;
+		self
+	}
+
+	pub fn build(self) -> State {
+		let mut abl = State::new();
+		abl.limits = self.limits;
+		abl.lax_variables = self.lax_variables;
+		if let Some(output) = self.output {
+			abl.output = output;
+		}
+		if let Some(input) = self.input {
+			abl.input = input;
+		}
+		for module in self.modules {
+			abl.reg_module(module);
+		}
+		abl
+	}
+}
+
+struct Global {
+	name: String,
+	var: Rc<RefCell<Value>>,
+	/// Globals get a slot when code referring to them is compiled, which
+	/// may be before they are defined
+	defined: bool
+}
 
 pub struct State {
-	globals: HashMap<String, Rc<RefCell<Value>>>,
-	scope: LinkedList<HashMap<String, Rc<RefCell<Value>>>>
+	globals: Vec<Global>,
+	global_slots: HashMap<String, usize>,
+	lax_variables: bool,
+	macros: HashMap<String, Macro>,
+	gensym: usize,
+	limits: Limits,
+	steps: u64,
+	depth: usize,
+	nesting: usize,
+	/// Macro expansions being compiled, one inside the other
+	expansions: usize,
+	interrupted: Arc<AtomicBool>,
+	output: Box<dyn Write>,
+	input: Box<dyn BufRead>
+}
+
+impl Default for State {
+	fn default() -> Self {
+		Self::new()
+	}
 }
 
 impl State {
 	pub fn new() -> Self {
 		State {
-			globals: HashMap::new(),
-			scope: LinkedList::new()
+			globals: Vec::new(),
+			global_slots: HashMap::new(),
+			lax_variables: false,
+			macros: HashMap::new(),
+			gensym: 0,
+			limits: Limits::default(),
+			steps: 0,
+			depth: 0,
+			nesting: 0,
+			expansions: 0,
+			interrupted: Arc::new(AtomicBool::new(false)),
+			output: Box::new(io::stdout()),
+			input: Box::new(BufReader::new(io::stdin()))
 		}
 	}
 
+	pub fn builder() -> StateBuilder {
+		StateBuilder::default()
+	}
+
+	pub fn limits(&self) -> &Limits {
+		&self.limits
+	}
+
+	pub fn set_limits(&mut self, limits: Limits) {
+		self.limits = limits;
+	}
+
+	/// Stream used by `print`, `debug` and other output builtins. Stdout by default.
+	pub fn output(&mut self) -> &mut dyn Write {
+		&mut self.output
+	}
+
+	pub fn set_output<W: Write + 'static>(&mut self, output: W) {
+		self.output = Box::new(output);
+	}
+
+	/// Stream used by `read-line`. Stdin by default.
+	pub fn input(&mut self) -> &mut dyn BufRead {
+		&mut self.input
+	}
+
+	pub fn set_input<R: BufRead + 'static>(&mut self, input: R) {
+		self.input = Box::new(input);
+	}
+
+	/// In lax mode, reading an undefined variable gives nil and `set` creates
+	/// it, instead of failing with [`ErrorKind::UndefinedVariable`].
+	pub fn set_lax_variables(&mut self, lax: bool) {
+		self.lax_variables = lax;
+	}
+
+	pub fn interrupt_handle(&self) -> InterruptHandle {
+		InterruptHandle {
+			flag: self.interrupted.clone()
+		}
+	}
+
+	/// Registers the modules of [`Module::DEFAULT`]. Access to the system
+	/// has to be granted separately with `reg_module(Module::Os)`.
 	pub fn reg_prelude(&mut self) {
-		self.globals.insert("debug".into(), Value::native_function(prelude::debug).var());
-		self.globals.insert("print".into(), Value::native_function(prelude::print).var());
-		self.globals.insert("head".into(), Value::native_function(prelude::head).var());
-		self.globals.insert("tail".into(), Value::native_function(prelude::tail).var());
-		self.globals.insert("cons".into(), Value::native_function(prelude::cons).var());
-		self.globals.insert("+".into(), Value::native_function(prelude::add).var());
-		self.globals.insert("-".into(), Value::native_function(prelude::sub).var());
-		self.globals.insert("*".into(), Value::native_function(prelude::mul).var());
-		self.globals.insert("/".into(), Value::native_function(prelude::div).var());
-		self.globals.insert("sqrt".into(), Value::native_function(prelude::sqrt).var());
-		self.globals.insert("=".into(), Value::native_function(prelude::eq).var());
-		self.globals.insert("/=".into(), Value::native_function(prelude::ne).var());
-		self.globals.insert("<".into(), Value::native_function(prelude::lt).var());
-		self.globals.insert("<=".into(), Value::native_function(prelude::le).var());
-		self.globals.insert(">".into(), Value::native_function(prelude::gt).var());
-		self.globals.insert(">=".into(), Value::native_function(prelude::ge).var());
-	}
-
-	pub fn get_var(&mut self, name: &str) -> Rc<RefCell<Value>> {
-		match self.get_local(name) {
-			Some(res) => res,
-			None => match self.globals.get(name) {
-				Some(var) => var.clone(),
-				None => {
-					self.globals.insert(name.into(), Value::nil().var());
-					self.globals.get(name).unwrap().clone()
-				} 
-			}
+		for module in Module::DEFAULT {
+			self.reg_module(module);
 		}
 	}
 
-	pub fn get_local(&mut self, name: &str) -> Option<Rc<RefCell<Value>>> {
-		for scope in &self.scope {
-			if let Some(var) = scope.get(name) {
-				return Some(var.clone());
+	pub fn reg_module(&mut self, module: Module) {
+		match module {
+			Module::Core => {
+				self.register("head", prelude::head);
+				self.register("tail", prelude::tail);
+				self.register("cons", prelude::cons);
+				self.register("list", prelude::list);
+				self.register("read-string", prelude::read_string);
+				self.register("eval", prelude::eval);
+				self.register("macroexpand-1", prelude::macroexpand_1);
+				self.register("macroexpand", prelude::macroexpand);
+				self.register("=", prelude::eq);
+				self.register("/=", prelude::ne);
+			}
+			Module::Math => {
+				self.register("+", prelude::add);
+				self.register("-", prelude::sub);
+				self.register("*", prelude::mul);
+				self.register("/", prelude::div);
+				self.register_typed("sqrt", f64::sqrt);
+				self.register("<", prelude::lt);
+				self.register("<=", prelude::le);
+				self.register(">", prelude::gt);
+				self.register(">=", prelude::ge);
+			}
+			Module::Io => {
+				self.register("debug", prelude::debug);
+				self.register("print", prelude::print);
+				self.register("read-line", prelude::read_line);
+			}
+			Module::Os => {
+				self.register_typed("getenv", prelude::getenv);
+				self.register_typed("time", prelude::time);
+				self.register_typed("read-file", prelude::read_file);
+				self.register_typed("write-file", prelude::write_file);
 			}
 		}
-		None
 	}
 
-	pub fn set_local(&mut self, name: &str, value: Value) {
-		let value = value.var();
-		match self.scope.front_mut() {
-			Some(scope) => scope.insert(name.into(), value),
-			None => self.globals.insert(name.into(), value)
-		};
+	/// Parses `source` and executes it, returning the value of the last expression.
+	pub fn eval_source(&mut self, source: Source) -> Result<Value, Error> {
+		let actions = parser::parse(source)?;
+		self.begin();
+		self.execute(&actions[..])
+	}
+
+	/// Evaluates code held in memory. Diagnostics refer to it as `<string>`.
+	pub fn eval_str(&mut self, src: &str) -> Result<Value, Error> {
+		self.eval_source(Source::string("<string>", src))
+	}
+
+	pub fn eval_file(&mut self, path: &str) -> Result<Value, Error> {
+		let source = Source::file(path)
+			.map_err(|_| Error::new(ErrorKind::File(path.into())))?;
+		self.eval_source(source)
+	}
+
+	/// Binds a host function to a global name. The function may be a closure
+	/// capturing Rust state.
+	pub fn register<F>(&mut self, name: &str, func: F)
+	where F: Fn(&mut State, &[Value]) -> Result<Value, Error> + 'static {
+		self.set_global(name, Value::native_function(func));
+	}
+
+	/// Binds a typed Rust function to a global name. Arguments are converted
+	/// with [`FromValue`](crate::value::convert::FromValue), so wrong argument
+	/// counts and types are reported without any code in `func`.
+	pub fn register_typed<Args, F: TypedFunction<Args>>(&mut self, name: &str, func: F) {
+		let func = Function { val: FunctionVal::Native(func.into_native()), captures: Vec::new() };
+		self.set_global(name, Value::Function(Rc::new(func)));
+	}
+
+	/// Reads the current value of a global variable without creating it.
+	pub fn get_global(&self, name: &str) -> Option<Value> {
+		let global = &self.globals[*self.global_slots.get(name)?];
+		global.defined.then(|| global.var.borrow().clone())
 	}
 
-	pub(crate) fn push_scope(&mut self, scope: HashMap<String, Rc<RefCell<Value>>>) {
-		self.scope.push_front(scope);
+	/// Assigns a global variable, defining it if it doesn't exist yet.
+	pub fn set_global(&mut self, name: &str, value: Value) {
+		let slot = self.global_slot(name);
+		self.assign_global(slot, value);
 	}
 
-	pub(crate) fn pop_scope(&mut self) {
-		self.scope.pop_front();
+	/// Calls a Lisp (or native) function from Rust.
+	pub fn call(&mut self, func: &Value, args: &[Value]) -> Result<Value, Error> {
+		self.begin();
+		self.call_value(func.clone(), List::from_slice(args), &Location::host())
 	}
 
-	pub fn execute(&mut self, actions: &[Action]) -> Result<Value, Error> {
-		let mut ret = Value::nil();
-		for action in actions {
-			ret = self.eval(action)?;
+	/// Calls the function stored in global variable `name`.
+	pub fn call_global(&mut self, name: &str, args: &[Value]) -> Result<Value, Error> {
+		match self.get_global(name) {
+			Some(func) => self.call(&func, args),
+			None => Err(self.undefined(name, std::iter::empty()))
 		}
-		Ok(ret)
 	}
 
-	fn eval(&mut self, action: &Action) -> Result<Value, Error> {
-		match &action.val {
-			ActionVal::Literal(val) => Ok(val.clone()),
-			ActionVal::Ident(name) => Ok(self.get_var(&name).borrow().clone()),
-			ActionVal::Group { content, quoted: true } => {
-				let content = self.eval_list(&content[..])?;
-				Ok(Value::List(content))
-			}
-			ActionVal::Group { content, quoted: false } => {
-				if content.len() == 0 {
-					Ok(Value::nil())
-				} else {
-					match &content[0].val {
-						ActionVal::Ident(action) if action == "do" => self.execute(&content[1..]),
-						ActionVal::Ident(action) if action == "let" => self.process_let_content(&content[1..]),
-						ActionVal::Ident(action) if action == "set" => self.process_set_content(&content[1..]),
-						ActionVal::Ident(action) if action == "if" => {
-							if content.len() == 4 {
-								let cond = self.eval(&content[1])?;
-								if cond.into() {
-									self.eval(&content[2])
-								} else {
-									self.eval(&content[3])
-								}
-							} else {
-								Err(Error::new_at(ErrorKind::Syntax, content[0].location.clone()))
-							}
-						}
-						ActionVal::Ident(action) if action == "while" => {
-							if content.len() == 3 {
-								while self.eval(&content[1])?.into() {
-									self.execute(&content[2..])?;
-								}
-								Ok(Value::nil())
-							} else {
-								Err(Error::new_at(ErrorKind::Syntax, content[0].location.clone()))
-							}
-						}
-						ActionVal::Ident(action) if action == "function" => {
-							if content.len() >= 4 {
-								let name = if let ActionVal::Ident(name) = &content[1].val {
-									name
-								} else {
-									return Err(Error::new_at(ErrorKind::Syntax, content[1].location.clone()));
-								};
-
-								let args = if let ActionVal::Group { content, .. } = &content[2].val {
-									content
-								} else {
-									return Err(Error::new_at(ErrorKind::Syntax, content[2].location.clone()));
-								};
-
-								let func = Self::create_function(&args[..], &content[3..])?;
-								let func = Value::Function(Rc::new(func));
-								self.set_local(name, func.clone());
-								Ok(func)
-							} else {
-								Err(Error::new_at(ErrorKind::Syntax, content[0].location.clone()))
-							}
-						}
-						ActionVal::Ident(action) if action == "funcap" => {
-							if content.len() >= 5 {
-								let name = if let ActionVal::Ident(name) = &content[1].val {
-									name
-								} else {
-									return Err(Error::new_at(ErrorKind::Syntax, content[1].location.clone()));
-								};
-
-								let args = if let ActionVal::Group { content, .. } = &content[2].val {
-									content
-								} else {
-									return Err(Error::new_at(ErrorKind::Syntax, content[2].location.clone()));
-								};
-
-								let captures = if let ActionVal::Group { content, .. } = &content[3].val {
-									content
-								} else {
-									return Err(Error::new_at(ErrorKind::Syntax, content[3].location.clone()));
-								};
-
-								let mut func = Self::create_function(&args[..], &content[4..])?;
-								for cap in captures {
-									if let ActionVal::Ident(name) = &cap.val {
-										func.captures.insert(name.clone(), self.get_var(name));
-									}
-								}
-
-								let func = Value::Function(Rc::new(func));
-								self.set_local(name, func.clone());
-								Ok(func)
-							} else {
-								Err(Error::new_at(ErrorKind::Syntax, content[0].location.clone()))
-							}
-						}
-						ActionVal::Ident(action) if action == "object" => {
-							let mut object = HashMap::new();
-							for item in &content[1..] {
-								if let ActionVal::Ident(name) = &item.val {
-									let var = self.get_var(name);
-									object.insert(name.into(), var);
-								} else {
-									return Err(Error::new_at(ErrorKind::Syntax, content[0].location.clone()));
-								}
-							}
-							Ok(Value::Object(Rc::new(object)))
-						}
-						_ => {
-							let data = self.eval_list(&content[..])?;
-							let func = data.head().unwrap();
-							let args = data.tail();
-							func.call(self, args, &content[0].location)
-						}
-					}
-				}
+	/// Slot of global variable `name`, created undefined when the name is new.
+	pub(crate) fn global_slot(&mut self, name: &str) -> usize {
+		match self.global_slots.get(name) {
+			Some(&slot) => slot,
+			None => {
+				self.globals.push(Global {
+					name: name.into(),
+					var: Value::nil().var(),
+					defined: false
+				});
+				self.global_slots.insert(name.into(), self.globals.len() - 1);
+				self.globals.len() - 1
 			}
 		}
 	}
 
-	fn eval_list(&mut self, actions: &[Action]) -> Result<Rc<List>, Error> {
-		if actions.len() == 0 {
-			Ok(List::new())
+	/// Variable of global `slot`, unless it's undefined and lax mode is off.
+	pub(crate) fn global_var(&self, slot: usize) -> Option<&Rc<RefCell<Value>>> {
+		let global = &self.globals[slot];
+		(global.defined || self.lax_variables).then_some(&global.var)
+	}
+
+	pub(crate) fn global_name(&self, slot: usize) -> &str {
+		&self.globals[slot].name
+	}
+
+	pub(crate) fn assign_global(&mut self, slot: usize, value: Value) {
+		let global = &mut self.globals[slot];
+		*global.var.borrow_mut() = value;
+		global.defined = true;
+	}
+
+	/// Defines global `slot` with a `let`. Redefining makes a new variable,
+	/// so closures and objects holding the old one keep its value.
+	pub(crate) fn define_global(&mut self, slot: usize, value: Value) {
+		let global = &mut self.globals[slot];
+		if global.defined {
+			global.var = value.var();
 		} else {
-			let val = self.eval(&actions[0])?;
-			let next = self.eval_list(&actions[1..])?;
-			Ok(next.push_front(val))
+			self.assign_global(slot, value);
 		}
 	}
 
-	fn process_let_content(&mut self, content: &[Action]) -> Result<Value, Error> {
-		if content.len() > 0 {
-			match &content[0].val {
-				ActionVal::Ident(name) => {
-					let value = if content.len() >= 2 {
-						self.eval(&content[1])?
-					} else {
-						Value::nil()
-					};
-					self.set_local(name, value.clone());
-					if content.len() > 2 {
-						self.process_let_content(&content[2..])
-					} else {
-						Ok(value)
-					}
-				}
-				_ => Err(Error::new_at(ErrorKind::Syntax, content[0].location.clone()))
+	/// Whether global `name` is defined, regardless of lax mode.
+	pub(crate) fn is_defined(&self, name: &str) -> bool {
+		self.global_slots.get(name).is_some_and(|&slot| self.globals[slot].defined)
+	}
+
+	/// Error for undefined variable `name`, suggesting one of the defined
+	/// globals or `locals` instead.
+	pub(crate) fn undefined<'a>(&'a self, name: &str, locals: impl Iterator<Item = &'a str>) -> Error {
+		let globals = self.globals.iter()
+			.filter(|global| global.defined)
+			.map(|global| global.name.as_str());
+		Error::new(ErrorKind::UndefinedVariable {
+			name: name.into(),
+			suggestion: error::closest_name(name, locals.chain(globals))
+		})
+	}
+
+	pub(crate) fn define_macro(&mut self, name: &str, definition: Macro) {
+		self.macros.insert(name.into(), definition);
+	}
+
+	/// Starts a fresh step budget and forgets stale interrupts, unless called
+	/// from inside a running evaluation.
+	fn begin(&mut self) {
+		if self.nesting == 0 {
+			self.steps = 0;
+			self.interrupted.store(false, Ordering::Relaxed);
+		}
+	}
+
+	/// Counts an executed instruction. Errors get the instruction's location
+	/// from the caller.
+	fn step(&mut self) -> Result<(), Error> {
+		if self.interrupted.load(Ordering::Relaxed) && self.interrupted.swap(false, Ordering::Relaxed) {
+			return Err(Error::new(ErrorKind::Interrupted));
+		}
+		self.steps += 1;
+		match self.limits.max_steps {
+			Some(max) if self.steps > max => Err(Error::new(ErrorKind::LimitExceeded(Limit::Steps))),
+			_ => Ok(())
+		}
+	}
+
+	/// Counts the expansion of a macro whose result is about to be compiled.
+	pub(crate) fn enter_expansion(&mut self) -> Result<(), Error> {
+		self.step()?;
+		match self.limits.max_expansion_depth {
+			Some(max) if self.expansions >= max => Err(Error::new(ErrorKind::LimitExceeded(Limit::Expansion))),
+			_ => {
+				self.expansions += 1;
+				Ok(())
 			}
-		} else {
-			Ok(Value::nil())
 		}
 	}
 
-	fn process_set_content(&mut self, content: &[Action]) -> Result<Value, Error> {
-		if content.len() > 0 {
-			match &content[0].val {
-				ActionVal::Ident(name) => {
-					let value = if content.len() >= 2 {
-						self.eval(&content[1])?
-					} else {
-						Value::nil()
-					};
-					let var = self.get_var(name);
-					*var.borrow_mut() = value.clone();
-					if content.len() > 2 {
-						self.process_let_content(&content[2..])
-					} else {
-						Ok(value)
-					}
-				}
-				_ => Err(Error::new_at(ErrorKind::Syntax, content[0].location.clone()))
+	pub(crate) fn leave_expansion(&mut self) {
+		self.expansions -= 1;
+	}
+
+	fn enter_call(&mut self) -> Result<(), Error> {
+		match self.limits.max_depth {
+			Some(max) if self.depth >= max => Err(Error::new(ErrorKind::LimitExceeded(Limit::Depth))),
+			_ => {
+				self.depth += 1;
+				Ok(())
 			}
-		} else {
-			Ok(Value::nil())
 		}
 	}
 
-	fn create_function(raw_args: &[Action], actions: &[Action]) -> Result<Function, Error> {
-		let mut args = Vec::with_capacity(raw_args.len());
-		for arg in raw_args {
-			if let ActionVal::Ident(name) = &arg.val {
-				args.push(name.clone());
-			} else {
-				return Err(Error::new_at(ErrorKind::Syntax, arg.location.clone()));
+	fn leave_call(&mut self) {
+		self.depth -= 1;
+	}
+
+	/// Rejects lists and strings larger than the configured limits.
+	fn check_size(&self, value: &Value) -> Result<(), Error> {
+		let exceeded = match value {
+			Value::List(list) => match self.limits.max_list_len {
+				Some(max) if list.len() > max => Some(Limit::ListLength),
+				_ => None
+			}
+			Value::String(string) => match self.limits.max_string_len {
+				Some(max) if string.len() > max => Some(Limit::StringLength),
+				_ => None
+			}
+			_ => None
+		};
+		match exceeded {
+			Some(limit) => Err(Error::new(ErrorKind::LimitExceeded(limit))),
+			None => Ok(())
+		}
+	}
+
+	/// Expands `form` once if it is a call to a macro, returns `None` otherwise.
+	/// Code produced from data can only point at `location`.
+	pub fn expand_macro(&mut self, form: &Value, location: &Location) -> Result<Option<Value>, Error> {
+		let form = self.code_from_value(form, location)?;
+		Ok(self.expand_macro_action(&form)?.map(|expanded| expanded.to_value()))
+	}
+
+	pub(crate) fn code_from_value(&self, value: &Value, location: &Location) -> Result<Action, Error> {
+		Action::from_value(value, location, self.limits.max_code_depth)
+	}
+
+	pub(crate) fn expand_macro_action(&mut self, form: &Action) -> Result<Option<Action>, Error> {
+		let name = match &form.val {
+			ActionVal::Group { content, quoted: false } => match content.first().map(|head| &head.val) {
+				Some(ActionVal::Ident(name)) => name,
+				_ => return Ok(None)
+			}
+			_ => return Ok(None)
+		};
+		match self.macros.get(name) {
+			Some(Macro::Procedural(func)) => {
+				let func = Value::Function(func.clone());
+				let args = match form.to_value() {
+					Value::List(list) => list.tail(),
+					_ => List::new()
+				};
+				let expanded = func.call(self, args, &form.location)?;
+				self.code_from_value(&expanded, &form.location).map(Some)
+			}
+			Some(Macro::Rules(rules)) => {
+				let rules = rules.clone();
+				rules.expand(form, &mut self.gensym).map(Some)
 			}
+			None => Ok(None)
 		}
-		let func = Function::lang(actions, args);
-		Ok(func)
 	}
 }
@@ -0,0 +1,249 @@
+use std::rc::Rc;
+
+use crate::{error::{Error, ErrorKind}, io::SharedBuffer, value::{Value, userdata::{MethodTable, UserData}}};
+
+use super::{State, Limits, Limit, Module};
+
+fn state() -> State {
+	let mut abl = State::new();
+	abl.reg_prelude();
+	abl
+}
+
+fn eval(src: &str) -> Value {
+	state().eval_str(src).unwrap_or_else(|err| panic!("{}", err))
+}
+
+fn eval_err(src: &str) -> Error {
+	match state().eval_str(src) {
+		Ok(val) => panic!("expected an error, got {:?}", val),
+		Err(err) => err
+	}
+}
+
+fn int(n: i64) -> Value {
+	Value::Number(n.into())
+}
+
+#[test]
+fn interrupts_while_idle_are_dropped() {
+	let mut abl = state();
+	abl.interrupt_handle().interrupt();
+	assert_eq!(abl.eval_str("(+ 1 2)").unwrap(), int(3));
+}
+
+#[test]
+fn closures_share_their_variables() {
+	let src = "
+		(function make () (do (let n 0) (let inc (lambda () (set n (+ n 1)))) (let get (lambda () n)) (object inc get)))
+		(let c (make))
+		(c'inc) (c'inc)
+		(c'get)";
+	assert_eq!(eval(src), int(2));
+}
+
+#[test]
+fn let_shadows_only_after_it_runs() {
+	assert_eq!(eval("(let n 5) (function f () (let n (+ n 1)) n) (list (f) n)"), Value::list(&[int(6), int(5)]));
+	assert_eq!(eval("(function outer (n) (function inner () (let n (+ n 1)) n) (inner)) (outer 1)"), int(2));
+	assert_eq!(eval("(let n 5) (function f () (let before n) (let n 1) (list before n)) (f)"), Value::list(&[int(5), int(1)]));
+	assert_eq!(eval("(let n 5) (function f () (let g (lambda () n)) (let n 1) (g)) (f)"), int(5));
+}
+
+#[test]
+fn each_let_makes_a_new_variable() {
+	let src = "
+		(function make ()
+			(let fs '())
+			(let i 0)
+			(while (< i 3) (do (let j i) (funcap g () (j) j) (set fs (cons g fs)) (set i (+ i 1))))
+			fs)
+		(let fs (make))
+		(list ((head fs)) ((head (tail fs))))";
+	assert_eq!(eval(src), Value::list(&[int(2), int(1)]));
+}
+
+#[test]
+fn local_functions_see_later_definitions() {
+	let src = "
+		(function parity (n)
+			(function is-even (n) (if (= n 0) 'even (is-odd (- n 1))))
+			(function is-odd (n) (if (= n 0) 'odd (is-even (- n 1))))
+			(is-even n))
+		(parity 7)";
+	assert_eq!(eval(src), Value::Atom("odd".into()));
+}
+
+#[test]
+fn tail_calls_dont_grow_the_call_depth() {
+	let mut abl = state();
+	abl.set_limits(Limits { max_depth: Some(100), ..Limits::default() });
+	let res = abl.eval_str("(function loop (n) (if (= n 0) 'done (loop (- n 1)))) (loop 100000)");
+	assert_eq!(res.unwrap(), Value::Atom("done".into()));
+	let res = abl.eval_str("(function count (n) (if (= n 0) 0 (+ 1 (count (- n 1))))) (count 1000)");
+	assert!(matches!(res.unwrap_err().kind(), ErrorKind::LimitExceeded(Limit::Depth)));
+}
+
+#[test]
+fn deeply_nested_lists_drop_without_recursing() {
+	let mut abl = state();
+	abl.eval_str("(let x ()) (let i 0) (while (< i 100000) (do (set x (list x)) (set i (+ i 1))))").unwrap();
+	assert_eq!(abl.eval_str("(= x x)").unwrap(), int(1));
+	let printed = abl.eval_str("(with-output-to-string (print (head x)) (debug x))").unwrap();
+	assert!(matches!(printed, Value::String(out) if out.starts_with("[[[") && out.ends_with("]]]\n")));
+	let err = abl.eval_str("(eval x)").unwrap_err();
+	assert!(matches!(err.kind(), ErrorKind::LimitExceeded(Limit::CodeDepth)));
+	drop(abl);
+}
+
+#[test]
+fn recursive_macros_hit_the_expansion_limit() {
+	let err = eval_err("(defmacro inf (x) `(inf ,x)) (inf 1)");
+	assert!(matches!(err.kind(), ErrorKind::LimitExceeded(Limit::Expansion)));
+	let err = eval_err("(define-syntax lp (syntax-rules () ((_ x) (list (lp x))))) (lp 1)");
+	assert!(matches!(err.kind(), ErrorKind::LimitExceeded(Limit::Expansion)));
+	assert_eq!(eval("(defmacro twice (x) `(list ,x ,x)) (twice 1)"), Value::list(&[int(1), int(1)]));
+}
+
+#[test]
+fn undefined_variables_get_suggestions() {
+	let err = eval_err("(prnt 1)");
+	assert!(matches!(err.kind(), ErrorKind::UndefinedVariable { suggestion: Some(name), .. } if name == "print"));
+	let err = eval_err("(let n 1) m");
+	assert!(matches!(err.kind(), ErrorKind::UndefinedVariable { suggestion: None, .. }));
+	let mut abl = state();
+	abl.set_lax_variables(true);
+	assert_eq!(abl.eval_str("undefined").unwrap(), Value::nil());
+}
+
+#[test]
+fn wrong_argument_counts_are_errors() {
+	let err = eval_err("(function f (a b) a) (f 1)");
+	let ErrorKind::ArityMismatch { expected, given, defined_at } = err.kind() else {
+		panic!("unexpected error {}", err);
+	};
+	assert_eq!((expected.min, expected.max, *given), (2, Some(2), 1));
+	assert!(defined_at.is_some());
+	assert!(matches!(eval_err("((lambda (a &optional b) a) 1 2 3)").kind(), ErrorKind::ArityMismatch { given: 3, .. }));
+}
+
+#[test]
+fn optional_rest_and_key_parameters() {
+	let src = "(function f (a &optional (b (* a 2)) c) (list a b c)) (list (f 1) (f 1 5 6))";
+	let nil = Value::nil();
+	assert_eq!(eval(src), Value::list(&[
+		Value::list(&[int(1), int(2), nil.clone()]),
+		Value::list(&[int(1), int(5), int(6)])
+	]));
+	assert_eq!(eval("((lambda (a &rest r) r) 1 2 3)"), Value::list(&[int(2), int(3)]));
+	assert_eq!(eval("((lambda (a &rest r) r) 1)"), nil);
+	let src = "(function window (&key (width 640) height) (list width height)) (list (window) (window 'height 2 'width 1))";
+	assert_eq!(eval(src), Value::list(&[
+		Value::list(&[int(640), nil]),
+		Value::list(&[int(1), int(2)])
+	]));
+	assert!(matches!(eval_err("((lambda (&key a) a) 'b 1)").kind(), ErrorKind::UnknownKeyword(name) if name == "b"));
+	assert!(matches!(eval_err("((lambda (&key a) a) 'a)").kind(), ErrorKind::MissingKeywordValue(_)));
+	assert!(matches!(eval_err("(function f (&rest) 1)").kind(), ErrorKind::Syntax(_)));
+}
+
+#[test]
+fn syntax_rules_rename_the_bindings_they_introduce() {
+	let src = "
+		(define-syntax swap (syntax-rules () ((_ a b) (do (let tmp a) (set a b) (set b tmp)))))
+		(let tmp 1)
+		(let other 2)
+		(swap tmp other)
+		(list tmp other)";
+	assert_eq!(eval(src), Value::list(&[int(2), int(1)]));
+	let src = "(define-syntax with-x (syntax-rules () ((_ e) ((lambda (x) e) 10)))) (let x 1) (with-x x)";
+	assert_eq!(eval(src), int(1));
+}
+
+#[test]
+fn syntax_rules_match_repetitions() {
+	let src = "
+		(define-syntax sums (syntax-rules () ((_ (a b) ...) (list (+ a b) ...))))
+		(list (sums (1 2) (3 4)) (sums))";
+	assert_eq!(eval(src), Value::list(&[Value::list(&[int(3), int(7)]), Value::nil()]));
+	let src = "(define-syntax last (syntax-rules () ((_ x ... y) y))) (last 1 2 3)";
+	assert_eq!(eval(src), int(3));
+}
+
+#[test]
+fn malformed_syntax_rules_are_syntax_errors() {
+	for src in [
+		"(define-syntax d (syntax-rules () ((_ (a a) ...) 1)))",
+		"(define-syntax d (syntax-rules () ((_ a b a) 1)))",
+		"(define-syntax d (syntax-rules () (x)))",
+		"(define-syntax d (syntax-rules () ((_ a) 1))) (d)"
+	] {
+		assert!(matches!(eval_err(src).kind(), ErrorKind::Syntax(_)), "{}", src);
+	}
+}
+
+#[test]
+fn top_level_let_rebinds_globals() {
+	let src = "(let v 1) (funcap g () (v) v) (function h () v) (let v 2) (list (g) (h))";
+	assert_eq!(eval(src), Value::list(&[int(1), int(2)]));
+	assert_eq!(eval("(let v 1) (funcap g () (v) v) (set v 2) (g)"), int(2));
+}
+
+#[test]
+fn errors_of_eval_and_macroexpand_point_at_the_call() {
+	let err = eval_err("(let e eval)\n(e (quote (nope 1)))");
+	assert!(matches!(err.kind(), ErrorKind::UndefinedVariable { .. }));
+	assert_eq!(err.location().map(|loc| loc.span.start), Some(14));
+	let err = eval_err("(define-syntax d (syntax-rules () ((_ a) 1)))\n(let me macroexpand)\n(me (quote (d)))");
+	assert!(err.location().is_some_and(|loc| !loc.is_host()));
+	assert!(matches!(err.kind(), ErrorKind::Syntax(errors) if errors.iter().all(|err| !err.location.is_host())));
+}
+
+#[test]
+fn output_can_be_captured() {
+	let buffer = SharedBuffer::new();
+	let mut abl = state();
+	abl.set_output(buffer.clone());
+	abl.eval_str("(print \"a\" 1) (debug \"b\")").unwrap();
+	assert_eq!(buffer.contents(), "a1String(\"b\")\n");
+	let captured = abl.eval_str("(with-output-to-string (print \"c\") (debug 2))").unwrap();
+	assert_eq!(captured, Value::String("c2\n".into()));
+	assert_eq!(buffer.contents(), "a1String(\"b\")\n");
+}
+
+#[test]
+fn typed_functions_check_their_arguments() {
+	let mut abl = state();
+	abl.register_typed("add", |a: i64, b: Option<i64>| a + b.unwrap_or(10));
+	assert_eq!(abl.eval_str("(list (add 1 2) (add 1))").unwrap(), Value::list(&[int(3), int(11)]));
+	let err = abl.eval_str("(add)").unwrap_err();
+	assert!(matches!(err.kind(), ErrorKind::ArityMismatch { given: 0, .. }));
+	let err = abl.eval_str("(add 1 2 3)").unwrap_err();
+	assert!(matches!(err.kind(), ErrorKind::ArityMismatch { given: 3, .. }));
+	let err = abl.eval_str("(add \"1\" 2)").unwrap_err();
+	assert!(matches!(err.kind(), ErrorKind::TypeMismatch { found: "string", .. }));
+}
+
+#[test]
+fn userdata_dispatches_methods_and_compares_by_identity() {
+	let mut abl = state();
+	let mut methods = MethodTable::new();
+	abl.register_typed("counter-get", |this: Rc<UserData>| *this.downcast_ref::<i64>().unwrap());
+	methods.insert("get".into(), abl.get_global("counter-get").unwrap());
+	let methods = Rc::new(methods);
+	abl.set_global("a", Value::user_data(UserData::new("counter", 7_i64).with_methods(methods.clone())));
+	abl.set_global("b", Value::user_data(UserData::new("counter", 7_i64).with_methods(methods)));
+	assert_eq!(abl.eval_str("(a'get)").unwrap(), int(7));
+	assert_eq!(abl.eval_str("(list (= a a) (= a b))").unwrap(), Value::list(&[int(1), int(0)]));
+	assert!(matches!(abl.eval_str("(a'set 1)").unwrap_err().kind(), ErrorKind::NotAFunction));
+}
+
+#[test]
+fn sandboxes_lack_os_builtins() {
+	let mut abl = State::builder().modules(&Module::DEFAULT).build();
+	assert_eq!(abl.eval_str("(+ 1 2)").unwrap(), int(3));
+	let err = abl.eval_str("(read-file \"Cargo.toml\")").unwrap_err();
+	assert!(matches!(err.kind(), ErrorKind::UndefinedVariable { name, .. } if name == "read-file"));
+	let mut abl = State::builder().modules(&Module::ALL).build();
+	assert!(matches!(abl.eval_str("(read-file \"Cargo.toml\")").unwrap(), Value::String(_)));
+}
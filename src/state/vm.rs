@@ -0,0 +1,428 @@
+use std::{collections::HashMap, io::Write, rc::Rc, cell::RefCell};
+
+use crate::{bytecode::{Chunk, Op, Proto, Var}, action::Action, compiler, error::{Error, ErrorKind}, io::SharedBuffer, location::Location, value::{Value, function::{Function, FunctionVal, Capture}, list::List}};
+
+use super::{State, Limit};
+
+/// Variables of a frame, shared with the closures capturing them.
+type Locals = Vec<Rc<RefCell<Value>>>;
+
+struct Frame {
+	chunk: Rc<Chunk>,
+	pc: usize,
+	/// Height of the value stack when the frame was entered
+	base: usize,
+	/// Arguments and local variables of the running function
+	locals: Locals,
+	/// Running function, `None` at top level
+	func: Option<Rc<Function>>,
+	/// Slots of arguments left out of the call, whose defaults the function
+	/// computes. Empty when it has no optional parameters.
+	missing: Vec<bool>,
+	/// Slots assigned by the call so far, by being arguments or by a `let`.
+	/// Empty for frames running in the variables of another one.
+	defined: Vec<bool>,
+	/// Whether the frame is a function call, as opposed to code running in
+	/// the variables of the frame below (top-level forms, code run by `eval`)
+	call: bool
+}
+
+/// Where a variable of a frame lives.
+enum Place<'a> {
+	Var(&'a Rc<RefCell<Value>>),
+	Global(usize)
+}
+
+impl Frame {
+	fn place(&self, var: Var) -> Place<'_> {
+		match var {
+			Var::Local(slot) => Place::Var(&self.locals[slot]),
+			Var::Captured(index) => match &self.func.as_ref().unwrap().captures[index] {
+				Capture::Var(var) => Place::Var(var),
+				Capture::Global(slot) => Place::Global(*slot)
+			}
+			Var::Global(slot) => Place::Global(slot)
+		}
+	}
+
+	/// Assigns local `slot` for a `let`. The first one assigns the variable
+	/// made when the frame was entered, which closures defined earlier may
+	/// already hold. Any later one, e.g. in a loop, makes a new variable, so
+	/// closures made before keep the one they captured.
+	fn define(&mut self, slot: usize, value: Value) {
+		match self.defined.get_mut(slot) {
+			Some(true) => self.locals[slot] = value.var(),
+			Some(defined) => {
+				*defined = true;
+				*self.locals[slot].borrow_mut() = value;
+			}
+			None => *self.locals[slot].borrow_mut() = value
+		}
+	}
+
+	/// Names of the variables in the scope of the running function.
+	fn names(&self) -> impl Iterator<Item = &str> {
+		let proto = match self.func.as_deref().map(|func| &func.val) {
+			Some(FunctionVal::Lang(proto)) => Some(proto),
+			_ => None
+		};
+		proto.into_iter()
+			.flat_map(|proto| proto.args.iter().chain(&proto.locals).chain(&proto.enclosing))
+			.map(String::as_str)
+	}
+}
+
+/// Stacks of one evaluation. Lisp calls push frames here instead of
+/// recursing, so their depth is bounded by memory and `max_depth`.
+#[derive(Default)]
+struct Machine {
+	frames: Vec<Frame>,
+	stack: Vec<Value>,
+	/// Streams replaced by `with-output-to-string`
+	captures: Vec<(Box<dyn Write>, SharedBuffer)>
+}
+
+impl Machine {
+	fn frame(&self) -> &Frame {
+		self.frames.last().unwrap()
+	}
+
+	fn chunk(&self) -> &Chunk {
+		&self.frame().chunk
+	}
+
+	fn pop(&mut self) -> Value {
+		self.stack.pop().unwrap()
+	}
+
+	fn pop_n(&mut self, count: usize) -> Vec<Value> {
+		self.stack.split_off(self.stack.len() - count)
+	}
+}
+
+impl State {
+	/// Compiles and runs `actions` one by one, returning the value of the last one.
+	pub fn execute(&mut self, actions: &[Action]) -> Result<Value, Error> {
+		let mut res = Value::nil();
+		for action in actions {
+			let chunk = Rc::new(compiler::compile(self, action, None)?);
+			res = self.run(&action.location, |_, machine| {
+				machine.frames.push(Frame { chunk, pc: 0, base: 0, locals: Vec::new(), func: None, missing: Vec::new(), defined: Vec::new(), call: false });
+				Ok(None)
+			})?;
+		}
+		Ok(res)
+	}
+
+	/// Calls a function, a method of an object or of userdata.
+	pub(crate) fn call_value(&mut self, func: Value, args: Rc<List>, location: &Location) -> Result<Value, Error> {
+		self.run(location, |abl, machine| {
+			abl.apply(machine, func, args.collect(), false).map_err(|err| err.or_at(location))
+		})
+	}
+
+	/// Runs the machine from the frame `start` pushes until it returns, unless
+	/// `start` produces the value right away. On error, the call depth and
+	/// output of the caller are restored.
+	fn run<F>(&mut self, location: &Location, start: F) -> Result<Value, Error>
+	where F: FnOnce(&mut Self, &mut Machine) -> Result<Option<Value>, Error> {
+		// Nested evaluations, started by native functions calling back into
+		// Lisp or by macros expanded while compiling, use the native stack
+		if self.limits.max_nesting.is_some_and(|max| self.nesting >= max) {
+			return Err(Error::new_at(ErrorKind::LimitExceeded(Limit::Nesting), location.clone()));
+		}
+		self.nesting += 1;
+		let mut machine = Machine::default();
+		let res = match start(self, &mut machine) {
+			Ok(Some(val)) => Ok(val),
+			Ok(None) => self.exec(&mut machine),
+			Err(err) => Err(err)
+		};
+		if res.is_err() {
+			self.unwind(machine);
+		}
+		self.nesting -= 1;
+		res
+	}
+
+	fn unwind(&mut self, machine: Machine) {
+		for frame in machine.frames {
+			if frame.call {
+				self.leave_call();
+			}
+		}
+		if let Some((output, _)) = machine.captures.into_iter().next() {
+			self.output = output;
+		}
+	}
+
+	/// Variable of global `slot`. Suggestions for an undefined one include the
+	/// variables of `frame`.
+	fn global<'a>(&'a self, frame: &'a Frame, slot: usize) -> Result<&'a Rc<RefCell<Value>>, Error> {
+		self.global_var(slot).ok_or_else(|| self.undefined(self.global_name(slot), frame.names()))
+	}
+
+	fn read(&self, frame: &Frame, var: Var) -> Result<Value, Error> {
+		match frame.place(var) {
+			Place::Var(var) => Ok(var.borrow().clone()),
+			Place::Global(slot) => Ok(self.global(frame, slot)?.borrow().clone())
+		}
+	}
+
+	/// Assigns a variable. Globals must be defined first unless `define` is set.
+	fn write(&mut self, frame: &Frame, var: Var, value: Value, define: bool) -> Result<(), Error> {
+		match frame.place(var) {
+			Place::Var(var) => *var.borrow_mut() = value,
+			Place::Global(slot) => {
+				if define {
+					self.define_global(slot, value);
+				} else {
+					self.global(frame, slot)?;
+					self.assign_global(slot, value);
+				}
+			}
+		}
+		Ok(())
+	}
+
+	fn exec(&mut self, m: &mut Machine) -> Result<Value, Error> {
+		loop {
+			let frame = m.frames.last_mut().unwrap();
+			let pc = frame.pc;
+			frame.pc += 1;
+			let op = frame.chunk.code[pc];
+			match self.exec_op(m, op, pc) {
+				Ok(None) => (),
+				Ok(Some(val)) => return Ok(val),
+				// Instructions only fail before touching the frame stack
+				Err(err) => return Err(err.or_at(m.chunk().location(pc)))
+			}
+		}
+	}
+
+	/// Executes one instruction. Returns the result once the bottom frame returns.
+	fn exec_op(&mut self, m: &mut Machine, op: Op, pc: usize) -> Result<Option<Value>, Error> {
+		self.step()?;
+		match op {
+			Op::Nil => m.stack.push(Value::nil()),
+			Op::Const(i) => {
+				let val = m.chunk().constants[i].clone();
+				m.stack.push(val);
+			}
+			Op::Get(var) => {
+				let val = self.read(m.frame(), var)?;
+				m.stack.push(val);
+			}
+			Op::Let(Var::Local(slot)) => {
+				let val = m.stack.last().unwrap().clone();
+				m.frames.last_mut().unwrap().define(slot, val);
+			}
+			Op::Set(var) | Op::Let(var) => {
+				let val = m.stack.last().unwrap().clone();
+				self.write(m.frame(), var, val, matches!(op, Op::Let(_)))?;
+			}
+			Op::Pop => {
+				m.pop();
+			}
+			Op::Jump(target) => m.frames.last_mut().unwrap().pc = target,
+			Op::JumpIfNot(target) => {
+				if !bool::from(m.pop()) {
+					m.frames.last_mut().unwrap().pc = target;
+				}
+			}
+			Op::JumpIfGiven(slot, target) => {
+				let frame = m.frames.last_mut().unwrap();
+				if !frame.missing.get(slot).copied().unwrap_or(false) {
+					frame.pc = target;
+				}
+			}
+			Op::Call(argc) | Op::TailCall(argc) => {
+				let args = m.pop_n(argc);
+				let func = m.pop();
+				if let Some(res) = self.apply(m, func, args, matches!(op, Op::TailCall(_)))? {
+					m.stack.push(res);
+				}
+			}
+			Op::Return => {
+				let res = m.pop();
+				let frame = m.frames.pop().unwrap();
+				m.stack.truncate(frame.base);
+				if frame.call {
+					self.leave_call();
+				}
+				if m.frames.is_empty() {
+					return Ok(Some(res));
+				}
+				m.stack.push(res);
+			}
+			Op::List(count) => {
+				let res = Value::list(&m.pop_n(count));
+				self.check_size(&res)?;
+				m.stack.push(res);
+			}
+			Op::Concat(count) => {
+				let mut items = Vec::new();
+				for part in m.pop_n(count) {
+					if let Value::List(list) = part {
+						items.extend(list.iter().cloned());
+					}
+				}
+				let res = Value::list(&items);
+				self.check_size(&res)?;
+				m.stack.push(res);
+			}
+			Op::ExpectList => match m.stack.last().unwrap() {
+				Value::List(_) => (),
+				other => return Err(Error::new(ErrorKind::TypeMismatch {
+					expected: "list",
+					found: other.type_name()
+				}))
+			}
+			Op::Function(i) => {
+				let frame = m.frame();
+				let proto = &frame.chunk.protos[i];
+				let mut func = Function::lang(proto.clone());
+				func.captures = frame.chunk.captures[i].iter().enumerate()
+					.map(|(index, &var)| match frame.place(var) {
+						Place::Var(var) => Capture::Var(var.clone()),
+						Place::Global(slot) => match self.global_var(slot) {
+							Some(var) if index < proto.listed => Capture::Var(var.clone()),
+							_ => Capture::Global(slot)
+						}
+					})
+					.collect();
+				m.stack.push(Value::Function(Rc::new(func)));
+			}
+			Op::Object(i) => {
+				let frame = m.frame();
+				let mut object = HashMap::new();
+				for (name, var) in &frame.chunk.objects[i] {
+					let var = match frame.place(*var) {
+						Place::Var(var) => var.clone(),
+						Place::Global(slot) => self.global(frame, slot)?.clone()
+					};
+					object.insert(name.clone(), var);
+				}
+				m.stack.push(Value::Object(Rc::new(object)));
+			}
+			Op::Eval => {
+				let code = self.code_from_value(&m.pop(), m.chunk().location(pc))?;
+				let frame = m.frame();
+				let (locals, func) = (frame.locals.clone(), frame.func.clone());
+				let context = match func.as_deref().map(|func| &func.val) {
+					Some(FunctionVal::Lang(proto)) => Some(proto.clone()),
+					_ => None
+				};
+				let chunk = Rc::new(compiler::compile(self, &code, context)?);
+				m.frames.push(Frame { chunk, pc: 0, base: m.stack.len(), locals, func, missing: Vec::new(), defined: Vec::new(), call: false });
+			}
+			Op::BeginCapture => {
+				let buffer = SharedBuffer::new();
+				let prev = std::mem::replace(&mut self.output, Box::new(buffer.clone()));
+				m.captures.push((prev, buffer));
+			}
+			Op::EndCapture => {
+				let (prev, buffer) = m.captures.pop().unwrap();
+				self.output = prev;
+				let res = Value::String(buffer.contents());
+				self.check_size(&res)?;
+				m.stack.push(res);
+			}
+		}
+		Ok(None)
+	}
+
+	/// Calls `func`. A native function returns its result, a Lisp function gets
+	/// a frame and returns `None`. In `tail` position that frame replaces the
+	/// caller's.
+	fn apply(&mut self, m: &mut Machine, mut func: Value, mut args: Vec<Value>, tail: bool) -> Result<Option<Value>, Error> {
+		let function = loop {
+			match func {
+				Value::Function(function) => break function,
+				_ => (func, args) = func.dispatch(args)?
+			}
+		};
+		let proto = match &function.val {
+			FunctionVal::Native(native) => {
+				let res = native(self, &args)?;
+				self.check_size(&res)?;
+				return Ok(Some(res));
+			}
+			FunctionVal::Lang(proto) => proto
+		};
+		if !proto.arity().accepts(args.len()) {
+			return Err(Error::new(ErrorKind::ArityMismatch {
+				expected: proto.arity(),
+				given: args.len(),
+				defined_at: Some(proto.location.clone())
+			}));
+		}
+
+		let (locals, missing) = bind(proto, args)?;
+		let defined = (0..proto.frame_size()).map(|slot| slot < proto.args.len()).collect();
+		let chunk = proto.chunk.clone();
+		match m.frames.last_mut() {
+			Some(current) if tail && current.call => {
+				m.stack.truncate(current.base);
+				current.chunk = chunk;
+				current.pc = 0;
+				current.locals = locals;
+				current.func = Some(function);
+				current.missing = missing;
+				current.defined = defined;
+			}
+			_ => {
+				self.enter_call()?;
+				m.frames.push(Frame { chunk, pc: 0, base: m.stack.len(), locals, func: Some(function), missing, defined, call: true });
+			}
+		}
+		Ok(None)
+	}
+}
+
+/// Variables of a call to `proto` with `args`, which fit its arity, and the
+/// slots of the arguments left out.
+fn bind(proto: &Proto, mut args: Vec<Value>) -> Result<(Locals, Vec<bool>), Error> {
+	let params = proto.params;
+	let positional = params.required + params.optional;
+	let extra = args.split_off(args.len().min(positional));
+	let mut missing = Vec::new();
+	if params.optional > 0 || params.keys > 0 {
+		missing = vec![false; proto.frame_size()];
+		missing[args.len()..positional].fill(true);
+	}
+	args.resize(positional, Value::nil());
+	if params.rest {
+		args.push(Value::list(&extra));
+	}
+	if params.keys > 0 {
+		let first = params.first_key();
+		args.resize(first + params.keys, Value::nil());
+		missing[first..first + params.keys].fill(true);
+		for pair in extra.chunks(2) {
+			let name = match &pair[0] {
+				Value::Atom(name) => name,
+				other => return Err(Error::new(ErrorKind::TypeMismatch {
+					expected: "atom",
+					found: other.type_name()
+				}))
+			};
+			let slot = match proto.args[first..].iter().position(|key| key == name) {
+				Some(index) => first + index,
+				None => return Err(Error::new(ErrorKind::UnknownKeyword(name.clone())))
+			};
+			let Some(value) = pair.get(1) else {
+				return Err(Error::new(ErrorKind::MissingKeywordValue(name.clone())));
+			};
+			args[slot] = value.clone();
+			missing[slot] = false;
+		}
+	}
+
+	let mut locals = Vec::with_capacity(proto.frame_size());
+	locals.extend(args.into_iter().map(Value::var));
+	for _ in &proto.locals {
+		locals.push(Value::nil().var());
+	}
+	Ok((locals, missing))
+}
@@ -1,25 +1,29 @@
-use std::{rc::Rc, cell::RefCell, fmt::{self, Debug}, f64::NAN, collections::HashMap};
+use std::{rc::Rc, cell::RefCell, fmt::{self, Debug, Display}, collections::HashMap};
 
 use function::Function;
 use list::List;
 use number::Number;
+use userdata::UserData;
 
 use crate::{state::State, error::Error, location::Location};
 
-use self::function::NativeFunction;
-
+pub mod convert;
 pub mod function;
 pub mod list;
 pub mod number;
+pub mod userdata;
 
 #[derive(Clone)]
 pub enum Value {
 	Atom(String),
+	/// Identifier held as data, produced by quoting code
+	Symbol(String),
 	Number(Number),
 	String(String),
 	List(Rc<List>),
 	Function(Rc<Function>),
-	Object(Rc<HashMap<String, Rc<RefCell<Value>>>>)
+	Object(Rc<HashMap<String, Rc<RefCell<Value>>>>),
+	UserData(Rc<UserData>)
 }
 
 impl Value {
@@ -27,73 +31,81 @@ impl Value {
 		Value::List(Rc::new(List::Nil))
 	}
 
-	pub fn native_function(func: NativeFunction) -> Self {
+	pub fn native_function<F>(func: F) -> Self
+	where F: Fn(&mut State, &[Value]) -> Result<Value, Error> + 'static {
 		Value::Function(Rc::new(Function::native(func)))
 	}
 
+	pub fn user_data(data: UserData) -> Self {
+		Value::UserData(Rc::new(data))
+	}
+
 	pub fn var(self) -> Rc<RefCell<Self>> {
 		Rc::new(RefCell::new(self))
 	}
 
-	#[allow(dead_code)]
 	pub fn list(data: &[Value]) -> Self {
 		Value::List(List::from_slice(data))
 	}
 
 	pub fn to_number(&self) -> Number {
 		match self {
-			Value::Number(num) => num.clone(),
-			_ => Number::Float(NAN)
+			Value::Number(num) => *num,
+			_ => Number::Float(f64::NAN)
 		}
 	}
 
-	pub fn to_string(&self) -> String {
+	pub fn type_name(&self) -> &'static str {
 		match self {
-			Value::String(val) => val.clone(),
-			_ => format!("{self:?}")
+			Value::Atom(_) => "atom",
+			Value::Symbol(_) => "symbol",
+			Value::Number(_) => "number",
+			Value::String(_) => "string",
+			Value::List(_) => "list",
+			Value::Function(_) => "function",
+			Value::Object(_) => "object",
+			Value::UserData(_) => "userdata"
 		}
 	}
 
 	pub fn call(&self, abl: &mut State, args: Rc<List>, loc: &Location) -> Result<Value, Error> {
-		use function::FunctionVal;
-		match self {
-			Value::Function(func) => {
-				abl.push_scope(func.captures.clone());
-				let res = match &func.val {
-					FunctionVal::Native(func) => func(abl, &args.collect()[..]),
-					FunctionVal::Lang { actions, args: arg_names } => {
-						let mut args = args;
-						for name in arg_names {
-							abl.set_local(name, args.head().unwrap_or(&Value::nil()).clone());
-							args = args.tail();
-						}
-						abl.execute(actions)
-					}
-				};
-				abl.pop_scope();
-				res
+		abl.call_value(self.clone(), args, loc)
+	}
+
+	/// Resolves a method call on an object or userdata to the method and the
+	/// arguments it is called with.
+	pub(crate) fn dispatch(&self, mut args: Vec<Value>) -> Result<(Value, Vec<Value>), Error> {
+		let not_a_function = || Error::new(crate::error::ErrorKind::NotAFunction);
+		if !matches!(self, Value::Object(_) | Value::UserData(_)) {
+			return Err(not_a_function());
+		}
+		let method = match args.first() {
+			Some(Value::Atom(method)) => method,
+			Some(_) => return Err(Error::new(crate::error::ErrorKind::BadIndex)),
+			None => return Err(not_a_function())
+		};
+		let method = match self {
+			Value::Object(object) => object.get(method).map(|method| method.borrow().clone()),
+			Value::UserData(data) => data.method(method).cloned(),
+			_ => None
+		};
+		match (self, method) {
+			(Value::Object(_), Some(method)) => {
+				args.remove(0);
+				Ok((method, args))
 			}
-			Value::Object(object) if !args.is_nil() => {
-				let method = args.head().unwrap();
-				if let Value::Atom(method) = method {
-					match object.get(method) {
-						Some(method) => {
-							method.borrow().call(abl, args.tail(), loc)
-						}
-						None => Err(Error::new_at(crate::error::ErrorKind::NotAFunction, loc.clone()))
-					}
-				} else {
-					Err(Error::new_at(crate::error::ErrorKind::BadIndex, loc.clone()))
-				}
+			(_, Some(method)) => {
+				args[0] = self.clone();
+				Ok((method, args))
 			}
-			_ => Err(Error::new_at(crate::error::ErrorKind::NotAFunction, loc.clone()))
+			(_, None) => Err(not_a_function())
 		}
 	}
 }
 
-impl Into<bool> for Value {
-	fn into(self) -> bool {
-		match self {
+impl From<Value> for bool {
+	fn from(val: Value) -> bool {
+		match val {
 			Value::List(list) => !list.is_nil(),
 			Value::Number(num) => match num {
 				Number::Int(x) => x != 0,
@@ -104,16 +116,27 @@ impl Into<bool> for Value {
 	}
 }
 
+impl Display for Value {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Value::String(val) => write!(f, "{val}"),
+			_ => write!(f, "{self:?}")
+		}
+	}
+}
+
 impl Debug for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
 			Self::Atom(atom) => write!(f, "'{atom}"),
+			Self::Symbol(name) => write!(f, "{name}"),
 			Self::Number(Number::Int(num)) => write!(f, "{num}"),
 			Self::Number(Number::Float(num)) => write!(f, "{num}"),
             Self::String(arg0) => f.debug_tuple("String").field(arg0).finish(),
-            Self::List(arg0) => write!(f, "{:?}", arg0.collect()),
+            Self::List(arg0) => write!(f, "{arg0:?}"),
             Self::Function(arg0) => f.debug_tuple("Function").field(arg0).finish(),
             Self::Object(arg0) => f.debug_tuple("Object").field(arg0).finish(),
+            Self::UserData(arg0) => write!(f, "{arg0:?}"),
         }
     }
 }
@@ -122,11 +145,13 @@ impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (Self::Atom(l0), Self::Atom(r0)) => l0 == r0,
+            (Self::Symbol(l0), Self::Symbol(r0)) => l0 == r0,
             (Self::Number(l0), Self::Number(r0)) => l0 == r0,
             (Self::String(l0), Self::String(r0)) => l0 == r0,
             (Self::List(l0), Self::List(r0)) => l0 == r0,
             (Self::Function(l0), Self::Function(r0)) => Rc::ptr_eq(l0, r0),
             (Self::Object(l0), Self::Object(r0)) => l0 == r0,
+            (Self::UserData(l0), Self::UserData(r0)) => Rc::ptr_eq(l0, r0),
 			_ => false
         }
     }
@@ -0,0 +1,243 @@
+use std::rc::Rc;
+
+use crate::{error::{Error, ErrorKind}, state::State};
+
+use super::{Value, list::List, number::Number, function::{Arity, NativeFunction}, userdata::UserData};
+
+/// Conversion from a Lisp value into a Rust type, used to unpack arguments of
+/// typed host functions.
+pub trait FromValue: Sized {
+	/// Whether a missing argument may be treated as `nil`.
+	const OPTIONAL: bool = false;
+
+	fn from_value(value: &Value) -> Result<Self, Error>;
+}
+
+/// Conversion from a Rust type into a Lisp value.
+pub trait IntoValue {
+	fn into_value(self) -> Value;
+}
+
+/// Return type of a typed host function: either a plain value or a `Result`
+/// whose error is reported as a Lisp error.
+pub trait IntoResult {
+	fn into_result(self) -> Result<Value, Error>;
+}
+
+/// Rust function that can be registered with [`State::register_typed`].
+/// Implemented for closures taking up to six [`FromValue`] arguments.
+pub trait TypedFunction<Args> {
+	fn into_native(self) -> NativeFunction;
+}
+
+fn mismatch(expected: &'static str, value: &Value) -> Error {
+	Error::new(ErrorKind::TypeMismatch {
+		expected,
+		found: value.type_name()
+	})
+}
+
+impl FromValue for Value {
+	fn from_value(value: &Value) -> Result<Self, Error> {
+		Ok(value.clone())
+	}
+}
+
+impl FromValue for i64 {
+	fn from_value(value: &Value) -> Result<Self, Error> {
+		match value {
+			Value::Number(Number::Int(val)) => Ok(*val),
+			Value::Number(Number::Float(val)) if val.fract() == 0. => Ok(*val as i64),
+			_ => Err(mismatch("integer", value))
+		}
+	}
+}
+
+impl FromValue for f64 {
+	fn from_value(value: &Value) -> Result<Self, Error> {
+		match value {
+			Value::Number(Number::Int(val)) => Ok(*val as f64),
+			Value::Number(Number::Float(val)) => Ok(*val),
+			_ => Err(mismatch("number", value))
+		}
+	}
+}
+
+impl FromValue for String {
+	fn from_value(value: &Value) -> Result<Self, Error> {
+		match value {
+			Value::String(val) => Ok(val.clone()),
+			_ => Err(mismatch("string", value))
+		}
+	}
+}
+
+impl FromValue for bool {
+	fn from_value(value: &Value) -> Result<Self, Error> {
+		Ok(value.clone().into())
+	}
+}
+
+impl FromValue for Rc<List> {
+	fn from_value(value: &Value) -> Result<Self, Error> {
+		match value {
+			Value::List(list) => Ok(list.clone()),
+			_ => Err(mismatch("list", value))
+		}
+	}
+}
+
+impl FromValue for Rc<UserData> {
+	fn from_value(value: &Value) -> Result<Self, Error> {
+		match value {
+			Value::UserData(data) => Ok(data.clone()),
+			_ => Err(mismatch("userdata", value))
+		}
+	}
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+	fn from_value(value: &Value) -> Result<Self, Error> {
+		match value {
+			Value::List(list) => list.collect().iter().map(T::from_value).collect(),
+			_ => Err(mismatch("list", value))
+		}
+	}
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+	const OPTIONAL: bool = true;
+
+	fn from_value(value: &Value) -> Result<Self, Error> {
+		match value {
+			Value::List(list) if list.is_nil() => Ok(None),
+			_ => T::from_value(value).map(Some)
+		}
+	}
+}
+
+impl IntoValue for Value {
+	fn into_value(self) -> Value {
+		self
+	}
+}
+
+impl IntoValue for () {
+	fn into_value(self) -> Value {
+		Value::nil()
+	}
+}
+
+impl IntoValue for i64 {
+	fn into_value(self) -> Value {
+		Value::Number(Number::Int(self))
+	}
+}
+
+impl IntoValue for f64 {
+	fn into_value(self) -> Value {
+		Value::Number(Number::Float(self))
+	}
+}
+
+impl IntoValue for String {
+	fn into_value(self) -> Value {
+		Value::String(self)
+	}
+}
+
+impl IntoValue for &str {
+	fn into_value(self) -> Value {
+		Value::String(self.into())
+	}
+}
+
+impl IntoValue for bool {
+	fn into_value(self) -> Value {
+		Value::Number(Number::Int(self as i64))
+	}
+}
+
+impl IntoValue for Rc<List> {
+	fn into_value(self) -> Value {
+		Value::List(self)
+	}
+}
+
+impl IntoValue for UserData {
+	fn into_value(self) -> Value {
+		Value::user_data(self)
+	}
+}
+
+impl IntoValue for Rc<UserData> {
+	fn into_value(self) -> Value {
+		Value::UserData(self)
+	}
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+	fn into_value(self) -> Value {
+		let items = self.into_iter().map(IntoValue::into_value).collect::<Vec<_>>();
+		Value::list(&items)
+	}
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+	fn into_value(self) -> Value {
+		match self {
+			Some(val) => val.into_value(),
+			None => Value::nil()
+		}
+	}
+}
+
+impl<T: IntoValue> IntoResult for T {
+	fn into_result(self) -> Result<Value, Error> {
+		Ok(self.into_value())
+	}
+}
+
+impl<T: IntoValue, E: std::fmt::Display> IntoResult for Result<T, E> {
+	fn into_result(self) -> Result<Value, Error> {
+		self.map(IntoValue::into_value)
+			.map_err(|err| Error::new(ErrorKind::Native(err.to_string())))
+	}
+}
+
+macro_rules! impl_typed_function {
+	($($arg:ident $val:ident),*) => {
+		impl<F, R, $($arg),*> TypedFunction<($($arg,)*)> for F
+		where
+			F: Fn($($arg),*) -> R + 'static,
+			R: IntoResult,
+			$($arg: FromValue),*
+		{
+			#[allow(unused_mut, unused_variables)]
+			fn into_native(self) -> NativeFunction {
+				Rc::new(move |_abl: &mut State, args: &[Value]| {
+					let optional: &[bool] = &[$($arg::OPTIONAL),*];
+					let arity = Arity {
+						min: optional.iter().rposition(|opt| !opt).map_or(0, |idx| idx + 1),
+						max: Some(optional.len())
+					};
+					if !arity.accepts(args.len()) {
+						return Err(Error::new(ErrorKind::ArityMismatch { expected: arity, given: args.len(), defined_at: None }));
+					}
+					let nil = Value::nil();
+					let mut args = args.iter();
+					$(let $val = $arg::from_value(args.next().unwrap_or(&nil))?;)*
+					self($($val),*).into_result()
+				})
+			}
+		}
+	};
+}
+
+impl_typed_function!();
+impl_typed_function!(A a);
+impl_typed_function!(A a, B b);
+impl_typed_function!(A a, B b, C c);
+impl_typed_function!(A a, B b, C c, D d);
+impl_typed_function!(A a, B b, C c, D d, E e);
+impl_typed_function!(A a, B b, C c, D d, E e, G g);
@@ -1,49 +1,103 @@
-use std::{fmt::{self, Debug}, collections::HashMap, rc::Rc, cell::RefCell};
+use std::{fmt::{self, Debug, Display}, rc::Rc, cell::RefCell};
 
-use crate::{state::State, error::Error, action::Action};
+use crate::{state::State, error::Error, location::Location};
+
+pub use crate::bytecode::Proto;
 
 use super::Value;
 
-pub type NativeFunction = fn(abl: &mut State, args: &[Value]) -> Result<Value, Error>;
+/// Function implemented by the host. Being a closure, it may own whatever state
+/// the host needs (handles, counters, configuration).
+pub type NativeFunction = Rc<dyn Fn(&mut State, &[Value]) -> Result<Value, Error>>;
 
-#[derive(Debug)]
 pub struct Function {
 	pub val: FunctionVal,
-	pub captures: HashMap<String, Rc<RefCell<Value>>>
+	/// Variables of enclosing scopes, in the order of the prototype's free variables
+	pub(crate) captures: Vec<Capture>
+}
+
+/// Variable captured by a function.
+pub(crate) enum Capture {
+	Var(Rc<RefCell<Value>>),
+	/// Global variable, which may only be defined later
+	Global(usize)
 }
 
 impl Function {
-	pub fn native(func: NativeFunction) -> Self {
+	pub fn native<F>(func: F) -> Self
+	where F: Fn(&mut State, &[Value]) -> Result<Value, Error> + 'static {
 		Function {
-			val: FunctionVal::Native(func),
-			captures: HashMap::new(),
+			val: FunctionVal::Native(Rc::new(func)),
+			captures: Vec::new()
 		}
 	}
 
-	pub fn lang(actions: &[Action], args: Vec<String>) -> Self {
+	/// Function created from a prototype without capturing anything.
+	pub fn lang(proto: Rc<Proto>) -> Self {
 		Function {
-			val: FunctionVal::Lang {
-				actions: actions.into(),
-				args
-			},
-			captures: HashMap::new()
+			val: FunctionVal::Lang(proto),
+			captures: Vec::new()
+		}
+	}
+
+	/// Where the function is defined, `None` for native functions.
+	pub fn location(&self) -> Option<&Location> {
+		match &self.val {
+			FunctionVal::Native(_) => None,
+			FunctionVal::Lang(proto) => Some(&proto.location)
+		}
+	}
+}
+
+impl Debug for Function {
+	/// Only names of captured variables are shown, their values may well
+	/// contain this very function.
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let captures: &[String] = match &self.val {
+			FunctionVal::Lang(proto) => &proto.free,
+			FunctionVal::Native(_) => &[]
+		};
+		f.debug_struct("Function")
+			.field("val", &self.val)
+			.field("captures", &captures)
+			.finish()
+	}
+}
+
+/// Number of arguments a function accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Arity {
+	pub min: usize,
+	/// `None` when any number of extra arguments is accepted
+	pub max: Option<usize>
+}
+
+impl Arity {
+	pub fn accepts(&self, count: usize) -> bool {
+		count >= self.min && self.max.is_none_or(|max| count <= max)
+	}
+}
+
+impl Display for Arity {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.max {
+			Some(max) if max == self.min => write!(f, "{max}"),
+			Some(max) => write!(f, "{} to {max}", self.min),
+			None => write!(f, "at least {}", self.min)
 		}
 	}
 }
 
 pub enum FunctionVal {
 	Native(NativeFunction),
-	Lang {
-		actions: Vec<Action>,
-		args: Vec<String>
-	}
+	Lang(Rc<Proto>)
 }
 
 impl Debug for FunctionVal {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		match self {
 			FunctionVal::Native(_) => write!(f, "<native function>"),
-			&FunctionVal::Lang { .. } => write!(f, "<function>")
+			FunctionVal::Lang(_) => write!(f, "<function>")
 		}
 	}
 }
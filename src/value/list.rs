@@ -1,8 +1,11 @@
-use std::rc::Rc;
+use std::{rc::Rc, fmt::{self, Debug}};
 
 use super::Value;
 
-#[derive(Debug, Clone, PartialEq)]
+/// Lists can be longer and more deeply nested than the native stack is deep,
+/// so dropping, comparing and printing below walk nested lists with an
+/// explicit stack instead of recursing.
+#[derive(Clone)]
 pub enum List {
 	Nil,
 	Item {
@@ -16,15 +19,8 @@ impl List {
 		Rc::new(List::Nil)
 	}
 
-	#[allow(dead_code)]
 	pub fn from_slice(data: &[Value]) -> Rc<Self> {
-		if data.len() == 0 {
-			Rc::new(List::Nil)
-		} else {
-			let value = data[0].clone();
-			let next = List::from_slice(&data[1..]);
-			Rc::new(List::Item { value, next })
-		}
+		data.iter().rev().fold(List::new(), |list, value| list.push_front(value.clone()))
 	}
 
 	pub fn push_front(self: Rc<Self>, value: Value) -> Rc<Self> {
@@ -48,34 +44,113 @@ impl List {
 		}
 	}
 
-	#[allow(dead_code)]
 	pub fn is_nil(&self) -> bool {
-		match self {
-			List::Nil => true,
-			_ => false
-		}
+		matches!(self, List::Nil)
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.is_nil()
 	}
 
 	pub fn len(&self) -> usize {
-		match self {
-			List::Nil => 0,
-			List::Item { next, .. } => next.len() + 1
-		}
+		self.iter().count()
+	}
+
+	pub fn iter(&self) -> Iter<'_> {
+		Iter { list: self }
 	}
 
 	pub fn collect(&self) -> Vec<Value> {
-		let mut res = Vec::with_capacity(self.len());
-		self.collect_rec(&mut res);
-		res
+		self.iter().cloned().collect()
 	}
+}
 
-	fn collect_rec(&self, res: &mut Vec<Value>) {
-		match self {
-			List::Nil => (),
+pub struct Iter<'a> {
+	list: &'a List
+}
+
+impl<'a> Iterator for Iter<'a> {
+	type Item = &'a Value;
+
+	fn next(&mut self) -> Option<&'a Value> {
+		match self.list {
+			List::Nil => None,
 			List::Item { value, next } => {
-				res.push(value.clone());
-				next.collect_rec(res);
+				self.list = next;
+				Some(value)
+			}
+		}
+	}
+}
+
+impl Drop for List {
+	/// Unlinks the tail and nested lists item by item, instead of letting each
+	/// drop the next. Lists shared with someone else are left alone.
+	fn drop(&mut self) {
+		let mut pending = Vec::new();
+		self.take_unique(&mut pending);
+		while let Some(list) = pending.pop() {
+			if let Ok(mut list) = Rc::try_unwrap(list) {
+				list.take_unique(&mut pending);
+			}
+		}
+	}
+}
+
+impl List {
+	/// Moves the tail and a list value to `pending` if this is their last owner.
+	fn take_unique(&mut self, pending: &mut Vec<Rc<List>>) {
+		if let List::Item { value, next } = self {
+			if Rc::strong_count(next) == 1 {
+				pending.push(std::mem::replace(next, List::new()));
+			}
+			if let Value::List(inner) = value {
+				if Rc::strong_count(inner) == 1 {
+					pending.push(std::mem::replace(inner, List::new()));
+				}
+			}
+		}
+	}
+}
+
+impl PartialEq for List {
+	fn eq(&self, other: &Self) -> bool {
+		let mut pending = vec![(self.iter(), other.iter())];
+		while let Some((left, right)) = pending.last_mut() {
+			match (left.next(), right.next()) {
+				(None, None) => {
+					pending.pop();
+				}
+				(Some(Value::List(left)), Some(Value::List(right))) => pending.push((left.iter(), right.iter())),
+				(Some(left), Some(right)) if left == right => (),
+				_ => return false
+			}
+		}
+		true
+	}
+}
+
+impl Debug for List {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("[")?;
+		let mut pending = vec![self.iter().peekable()];
+		while let Some(items) = pending.last_mut() {
+			match items.next() {
+				None => {
+					pending.pop();
+					f.write_str("]")?;
+				}
+				Some(Value::List(inner)) => {
+					f.write_str("[")?;
+					pending.push(inner.iter().peekable());
+					continue;
+				}
+				Some(value) => write!(f, "{value:?}")?
+			}
+			if pending.last_mut().is_some_and(|items| items.peek().is_some()) {
+				f.write_str(", ")?;
 			}
 		}
+		Ok(())
 	}
 }
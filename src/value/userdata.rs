@@ -0,0 +1,53 @@
+use std::{any::Any, collections::HashMap, fmt::{self, Debug}, rc::Rc};
+
+use super::Value;
+
+/// Methods shared by every handle of a host type, looked up by atom name.
+pub type MethodTable = HashMap<String, Value>;
+
+/// Opaque value owned by the host. Scripts can only pass it around, compare it
+/// by identity and call methods from its method table. A method receives the
+/// handle itself as its first argument, so `(handle'close)` calls the `close`
+/// method with `handle`.
+pub struct UserData {
+	type_name: String,
+	data: Rc<dyn Any>,
+	methods: Option<Rc<MethodTable>>
+}
+
+impl UserData {
+	pub fn new<T: Any>(type_name: &str, data: T) -> Self {
+		UserData {
+			type_name: type_name.into(),
+			data: Rc::new(data),
+			methods: None
+		}
+	}
+
+	pub fn with_methods(mut self, methods: Rc<MethodTable>) -> Self {
+		self.methods = Some(methods);
+		self
+	}
+
+	pub fn type_name(&self) -> &str {
+		&self.type_name
+	}
+
+	pub fn data(&self) -> &Rc<dyn Any> {
+		&self.data
+	}
+
+	pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+		self.data.downcast_ref()
+	}
+
+	pub fn method(&self, name: &str) -> Option<&Value> {
+		self.methods.as_ref()?.get(name)
+	}
+}
+
+impl Debug for UserData {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "<{} {:p}>", self.type_name, Rc::as_ptr(&self.data) as *const ())
+	}
+}